@@ -1,6 +1,6 @@
 use crate::structs::magnet::MagnetLink;
 use clap::{Parser, Subcommand};
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -38,6 +38,53 @@ pub enum Commands {
         #[arg()]
         torrent_file: String,
     },
+    /// Get the number of seeders, leechers and completed downloads of torrents from a tracker
+    /// ex: `cargo run scrape udp://tracker.example.com:6969 <info hash> <info hash>`
+    #[command(arg_required_else_help = true)]
+    Scrape {
+        /// The announce URL of the tracker
+        #[arg()]
+        tracker: String,
+
+        /// The hexadecimal info hashes of the torrents
+        #[arg(required = true)]
+        info_hashes: Vec<String>,
+    },
+    /// Create a torrent file from a file or a directory
+    /// ex: `cargo run create -o release.torrent -t udp://tracker.example.com:6969 target/release`
+    #[command(arg_required_else_help = true)]
+    Create {
+        /// Where to write the torrent file
+        #[arg(short, long)]
+        output: String,
+
+        /// The file or directory to share
+        #[arg()]
+        path: String,
+
+        /// A tier of trackers, the trackers of a tier separated by commas. Repeat for backup tiers
+        #[arg(short, long = "tracker")]
+        trackers: Vec<String>,
+
+        /// The URL of a web seed. Repeat for more
+        #[arg(short, long = "web-seed")]
+        web_seeds: Vec<String>,
+
+        #[arg(short, long)]
+        comment: Option<String>,
+
+        /// Only get peers from the trackers
+        #[arg(short, long)]
+        private: bool,
+
+        /// The tracker or site the torrent is made for
+        #[arg(short, long)]
+        source: Option<String>,
+
+        /// Number of bytes in each piece, picked from the size of the files when not given
+        #[arg(short = 'l', long)]
+        piece_length: Option<i32>,
+    },
     /// Create a handshake with a peer
     #[command(arg_required_else_help = true)]
     Handshake {
@@ -47,7 +94,7 @@ pub enum Commands {
 
         /// The peer to connect to
         #[arg()]
-        peer_address: SocketAddrV4,
+        peer_address: SocketAddr,
     },
     DownloadPiece {
         /// Download output destination
@@ -1,12 +1,14 @@
 // use anyhow::{Context, Error};
 // use bittorrent_starter_rust::structs::extension::Extension;
 // use bittorrent_starter_rust::structs::peers::{Peer, PeerList};
+use bittorrent_starter_rust::structs::seeder::{Seeder, PORT};
 // use bittorrent_starter_rust::structs::torrent::Torrent;
 // use bittorrent_starter_rust::utils::decoder::decode_bencoded_value;
 // use bittorrent_starter_rust::utils::files::write_file;
 // use clap::Parser;
 // use serde_bencode::from_bytes;
 // use std::fs;
+use std::sync::Arc;
 //
 // use std::io::{self, Write};
 // use bittorrent_starter_rust::structs::magnet::MagnetLink;
@@ -297,21 +299,30 @@
 // }
 
 
-#[macro_use]
 extern crate rocket;
 
-use anyhow::{Context, Error};
-use rocket::fs::TempFile;
+use anyhow::{anyhow, Context, Error};
+use bittorrent_starter_rust::cli::{Cli, Commands};
+use clap::Parser;
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::{post, routes, tokio};
+use rocket::fairing::AdHoc;
+use rocket::tokio::task::JoinSet;
+use rocket::{post, routes, tokio, Build, Rocket, State};
+use bittorrent_starter_rust::structs::create::{parse_tiers, CreateOptions};
+use bittorrent_starter_rust::structs::dht::{Dht, DhtConfig};
+use bittorrent_starter_rust::structs::lsd::Lsd;
+use bittorrent_starter_rust::structs::download::PickStrategy;
 use bittorrent_starter_rust::structs::extension::Extension;
+use bittorrent_starter_rust::structs::metadata::{fetch_metadata, fetch_piece_layers};
 use bittorrent_starter_rust::structs::peers::{Peer, PeerList};
 use bittorrent_starter_rust::structs::torrent::Torrent;
-use bittorrent_starter_rust::utils::files::write_file;
+use bittorrent_starter_rust::utils::trackers::{self, ScrapeFile, TrackerList};
+use hex::FromHex;
 use serde::Deserialize;
-use serde_bencode::from_bytes;
+use std::collections::HashMap;
 use std::fs;
+use std::path::PathBuf;
 
 use bittorrent_starter_rust::structs::magnet::MagnetLink;
 
@@ -320,6 +331,8 @@ use bittorrent_starter_rust::structs::magnet::MagnetLink;
 struct DownloadRequest {
     torrent_file_path: String,
     output_path: String,
+    #[serde(default)]
+    strategy: PickStrategy,
 }
 
 /// Request payload for MagnetDownload
@@ -327,101 +340,343 @@ struct DownloadRequest {
 struct MagnetDownloadRequest {
     magnet_link: String,
     magnet_output_path: String,
+    #[serde(default)]
+    strategy: PickStrategy,
+}
+
+/// Request payload for Create
+#[derive(Deserialize)]
+struct CreateRequest {
+    /// The file or directory to share
+    path: String,
+    /// Where to write the torrent file
+    torrent_file_path: String,
+    /// Whether to seed the files right away
+    #[serde(default)]
+    seed: bool,
+    #[serde(flatten)]
+    options: CreateOptions,
+}
+
+/// Request payload for Scrape
+#[derive(Deserialize)]
+struct ScrapeRequest {
+    /// The announce URL of the tracker
+    tracker: String,
+    /// The hexadecimal info hashes of the torrents
+    info_hashes: Vec<String>,
+}
+
+/// Tracker scrape handler: the statistics of each torrent, by info hash
+#[post("/scrape", data = "<scrape_req>")]
+async fn scrape(
+    scrape_req: Json<ScrapeRequest>,
+) -> Result<Json<HashMap<String, ScrapeFile>>, Json<String>> {
+    let req = scrape_req.into_inner();
+
+    let info_hashes = req
+        .info_hashes
+        .iter()
+        .map(<[u8; 20]>::from_hex)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Json(format!("Error parsing info hash: {}", e)))?;
+    let files = trackers::scrape(&req.tracker, &info_hashes)
+        .await
+        .map_err(|e| Json(format!("Error scraping tracker: {}", e)))?;
+
+    Ok(Json(req.info_hashes.into_iter().zip(files).collect()))
+}
+
+/// Torrent creation handler: writes the torrent file, and answers with its magnet link
+#[post("/create", data = "<create_req>")]
+async fn create_torrent(
+    create_req: Json<CreateRequest>,
+    seeder: &State<Arc<Seeder>>,
+    dht: &State<Option<Arc<Dht>>>,
+    lsd: &State<Option<Arc<Lsd>>>,
+) -> Result<Json<String>, Json<String>> {
+    let req = create_req.into_inner();
+    // Seeding needs the directory the files are in, which `.` or `..` don't give
+    let path = fs::canonicalize(&req.path)
+        .map_err(|e| Json(format!("Error reading {}: {}", req.path, e)))?;
+
+    // Hashing the files takes a while, and is done by threads of its own
+    let (source, options) = (path.clone(), req.options);
+    let mut torrent = tokio::task::spawn_blocking(move || Torrent::create(&source, &options))
+        .await
+        .map_err(|e| Json(format!("Error creating torrent: {}", e)))?
+        .map_err(|e| Json(format!("Error creating torrent: {}", e)))?;
+    let bytes = torrent
+        .to_bytes()
+        .map_err(|e| Json(format!("Error creating torrent: {}", e)))?;
+    fs::write(&req.torrent_file_path, bytes)
+        .map_err(|e| Json(format!("Error writing torrent file: {}", e)))?;
+
+    if req.seed {
+        // The files of a directory are found under `<output>/<name>`
+        let output = match (&torrent.info.files, path.parent()) {
+            (Some(_), Some(parent)) => parent.to_path_buf(),
+            _ => path,
+        };
+        torrent.dht = dht.inner().clone();
+        torrent.lsd = lsd.inner().clone();
+        seeder
+            .add(&torrent, &output.to_string_lossy())
+            .await
+            .map_err(|e| Json(format!("Error seeding torrent: {}", e)))?;
+    }
+    Ok(Json(MagnetLink::from_torrent(&torrent).to_string()))
 }
 
 /// Torrent file download handler
 #[post("/download", data = "<download_req>")]
-async fn download_torrent(download_req: Json<DownloadRequest>) -> Result<Status, Json<String>> {
+async fn download_torrent(
+    download_req: Json<DownloadRequest>,
+    seeder: &State<Arc<Seeder>>,
+    dht: &State<Option<Arc<Dht>>>,
+    lsd: &State<Option<Arc<Lsd>>>,
+) -> Result<Status, Json<String>> {
     let req = download_req.into_inner();
 
     let file = fs::read(&req.torrent_file_path)
         .context("Reading torrent file")
         .map_err(|e| Json(format!("Error: {}", e)))?;
-    let mut torrent: Torrent = from_bytes(&file)
-        .context("Parsing torrent file content")
-        .map_err(|e| Json(format!("Error: {}", e)))?;
+    let mut torrent = Torrent::from_bytes(&file).map_err(|e| Json(format!("Error: {}", e)))?;
+    torrent.strategy = req.strategy;
+    torrent.dht = dht.inner().clone();
+    torrent.lsd = lsd.inner().clone();
 
     let peers = torrent
         .get_available_peers()
         .await
         .map_err(|e| Json(format!("Error finding peers: {}", e)))?;
 
-    if let Ok(pieces) = torrent.download_torrent(peers, false).await {
-        let data = pieces.into_iter().flatten().collect::<Vec<u8>>();
-        write_file(&req.output_path, &data)
-            .map_err(|e| Json(format!("Error saving file: {}", e)))?;
-        Ok(Status::Ok)
-    } else {
-        Err(Json("Error downloading torrent".to_string()))
-    }
+    torrent
+        .download_torrent(peers, false, &req.output_path)
+        .await
+        .map_err(|e| Json(format!("Error downloading torrent: {}", e)))?;
+    seeder
+        .add(&torrent, &req.output_path)
+        .await
+        .map_err(|e| Json(format!("Error seeding torrent: {}", e)))?;
+    Ok(Status::Ok)
 }
 
 /// Magnet link download handler
 #[post("/magnet_download", data = "<magnet_req>")]
-async fn magnet_download(magnet_req: Json<MagnetDownloadRequest>) -> Result<Status, Json<String>> {
+async fn magnet_download(
+    magnet_req: Json<MagnetDownloadRequest>,
+    seeder: &State<Arc<Seeder>>,
+    dht: &State<Option<Arc<Dht>>>,
+    lsd: &State<Option<Arc<Lsd>>>,
+) -> Result<Status, Json<String>> {
     let req = magnet_req.into_inner();
     let magnet_link: MagnetLink = req
         .magnet_link
         .parse()
         .map_err(|e| Json(format!("Error parsing magnet link: {}", e)))?;
 
-    let peers = PeerList::get_peers_from(&magnet_link)
+    let peers = PeerList::get_peers_from(&magnet_link, dht.inner().as_ref())
         .await
         .map_err(|e| Json(format!("Error finding peers: {}", e)))?;
     let mut available_peers: Vec<Peer> = vec![];
-    let mut extension: Option<Extension> = None;
+    let mut metadata_peers: Vec<(Peer, Extension)> = vec![];
 
-    for peer in peers {
-        let mut peer = Peer::new(peer, &magnet_link.info_hash)
-            .await
-            .map_err(|e| Json(format!("Error creating peer: {}", e)))?;
-        peer.get_pieces()
-            .await
-            .map_err(|e| Json(format!("Error retrieving pieces: {}", e)))?;
-        let ext = peer
-            .send_ext_handshake()
-            .await
-            .map_err(|e| Json(format!("Error in handshake: {}", e)))?;
-        if extension.is_none() {
-            extension = Some(ext);
+    // Connect to all the peers at once, skipping the ones which fail
+    let info_hash = magnet_link.info_hash;
+    let mut join_set = JoinSet::new();
+    for address in peers {
+        join_set.spawn(async move {
+            let mut peer = Peer::new(address, &info_hash)
+                .await
+                .context("Creating peer")?;
+            peer.get_pieces().await.context("Retrieving pieces")?;
+            let ext = peer.send_ext_handshake().await.context("Extension handshake")?;
+            Ok::<_, Error>((peer, ext))
+        });
+    }
+    while let Some(result) = join_set.join_next().await {
+        match result {
+            Ok(Ok((peer, ext))) => {
+                metadata_peers.push((peer.clone(), ext));
+                available_peers.push(peer);
+            }
+            Ok(Err(e)) => eprintln!("Skipping peer: {:?}", e),
+            Err(e) => eprintln!("Joining peer connection: {:?}", e),
         }
-        available_peers.push(peer);
     }
 
     if !available_peers.is_empty() {
-        let info = available_peers[0]
-            .get_extension_info(&extension.unwrap(), &magnet_link)
+        let mut info = fetch_metadata(&metadata_peers, &magnet_link.info_hash)
             .await
             .map_err(|e| Json(format!("Error retrieving torrent info: {}", e)))?;
+        fetch_piece_layers(&mut available_peers, &mut info)
+            .await
+            .map_err(|e| Json(format!("Error retrieving piece layers: {}", e)))?;
         let mut torrent = Torrent {
-            announce: magnet_link.tracker_url,
+            announce: magnet_link.trackers.first().cloned().unwrap_or_default(),
+            announce_list: magnet_link.tiers(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: magnet_link.web_seeds.clone(),
             info,
+            stats: Default::default(),
+            strategy: req.strategy,
+            trackers: Arc::new(TrackerList::new(magnet_link.tiers())),
+            announcer: None,
+            wanted: None,
+            dht: dht.inner().clone(),
+            lsd: lsd.inner().clone(),
         };
-        if let Ok(pieces) = torrent.download_torrent(available_peers, true).await {
-            let data = pieces.into_iter().flatten().collect::<Vec<u8>>();
-            write_file(&req.magnet_output_path, &data)
-                .map_err(|e| Json(format!("Error saving file: {}", e)))?;
-            Ok(Status::Ok)
-        } else {
-            Err(Json("Error downloading torrent".to_string()))
+        if !magnet_link.select_only.is_empty() {
+            torrent.select_files(|index| magnet_link.is_selected(index));
         }
+        torrent
+            .download_torrent(available_peers, true, &req.magnet_output_path)
+            .await
+            .map_err(|e| Json(format!("Error downloading torrent: {}", e)))?;
+        seeder
+            .add(&torrent, &req.magnet_output_path)
+            .await
+            .map_err(|e| Json(format!("Error seeding torrent: {}", e)))?;
+        Ok(Status::Ok)
     } else {
         Err(Json("No available peers found".to_string()))
     }
 }
 
 use rocket::fs::{FileServer, NamedFile};
-use std::path::PathBuf;
 
 #[rocket::get("/")]
 async fn index() -> Option<NamedFile> {
     NamedFile::open("static/index.html").await.ok()
 }
 
-/// Rocket entry point
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
-        .mount("/", routes![download_torrent, magnet_download, index])
+/// Entry point: runs the subcommand when one is given, and serves the web interface otherwise
+#[rocket::main]
+async fn main() -> Result<(), Error> {
+    if std::env::args_os().len() > 1 {
+        return run_command(Cli::parse().subcmd).await;
+    }
+    rocket().await.launch().await?;
+    Ok(())
+}
+
+/// Run a subcommand of the command line
+async fn run_command(command: Commands) -> Result<(), Error> {
+    match command {
+        Commands::Scrape {
+            tracker,
+            info_hashes,
+        } => {
+            let hashes = info_hashes
+                .iter()
+                .map(<[u8; 20]>::from_hex)
+                .collect::<Result<Vec<_>, _>>()
+                .context("Parsing info hash")?;
+            let files = trackers::scrape(&tracker, &hashes).await?;
+            for (info_hash, file) in info_hashes.iter().zip(files) {
+                println!(
+                    "{}: {} seeders, {} leechers, {} downloads",
+                    info_hash, file.complete, file.incomplete, file.downloaded
+                );
+            }
+            Ok(())
+        }
+        Commands::Create {
+            output,
+            path,
+            trackers,
+            web_seeds,
+            comment,
+            private,
+            source,
+            piece_length,
+        } => {
+            let options = CreateOptions {
+                trackers: parse_tiers(&trackers),
+                web_seeds,
+                comment,
+                private,
+                source,
+                piece_length,
+                ..Default::default()
+            };
+            let torrent = Torrent::create(&PathBuf::from(path), &options)?;
+            fs::write(&output, torrent.to_bytes()?).context("Writing torrent file")?;
+            println!("{}", MagnetLink::from_torrent(&torrent));
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "This command is only available from the web interface, started without arguments"
+        )),
+    }
+}
+
+/// Rocket instance, with the seeder, DHT and Local Service Discovery it shares with the routes
+async fn rocket() -> Rocket<Build> {
+    let seeder = Arc::new(Seeder::new());
+    let listener = seeder.clone();
+
+    let rocket = rocket::build();
+    let dht_config: DhtConfig = rocket.figment().extract_inner("dht").unwrap_or_default();
+    let dht = match Dht::bind(dht_config).await {
+        Ok(dht) => Some(dht),
+        Err(e) => {
+            eprintln!("DHT disabled: {:?}", e);
+            None
+        }
+    };
+    let bootstrap = dht.clone();
+    let lsd = match Lsd::bind(PORT).await {
+        Ok(lsd) => Some(lsd),
+        Err(e) => {
+            eprintln!("Local Service Discovery disabled: {:?}", e);
+            None
+        }
+    };
+
+    rocket
+        .manage(seeder)
+        .manage(dht)
+        .manage(lsd)
+        .attach(AdHoc::on_liftoff("Seeder", |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    if let Err(e) = listener.listen(PORT).await {
+                        eprintln!("Seeder stopped: {:?}", e);
+                    }
+                });
+            })
+        }))
+        .attach(AdHoc::on_liftoff("DHT bootstrap", |_| {
+            Box::pin(async move {
+                if let Some(dht) = bootstrap {
+                    tokio::spawn(async move {
+                        match dht.bootstrap().await {
+                            Ok(nodes) => println!("Joined the DHT through {} nodes", nodes),
+                            Err(e) => eprintln!("Joining the DHT: {:?}", e),
+                        }
+                    });
+                }
+            })
+        }))
+        .attach(AdHoc::on_shutdown("Announce stop", |rocket| {
+            Box::pin(async move {
+                if let Some(seeder) = rocket.state::<Arc<Seeder>>() {
+                    seeder.shutdown().await;
+                }
+                if let Some(Some(dht)) = rocket.state::<Option<Arc<Dht>>>() {
+                    if let Err(e) = dht.save() {
+                        eprintln!("{:?}", e);
+                    }
+                }
+            })
+        }))
+        .mount(
+            "/",
+            routes![create_torrent, download_torrent, magnet_download, scrape, index],
+        )
         .mount("/static", FileServer::from("static"))
 }
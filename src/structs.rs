@@ -1,7 +1,18 @@
+pub mod announcer;
+pub mod bitfield;
+pub mod create;
+pub mod dht;
+pub mod download;
 pub mod extension;
 mod handshake;
+pub mod lsd;
 pub mod magnet;
+pub mod merkle;
 pub mod message;
+pub mod metadata;
 pub mod peers;
+pub mod pex;
 pub mod request;
+pub mod seeder;
+pub mod storage;
 pub mod torrent;
@@ -0,0 +1,238 @@
+use crate::structs::dht::Dht;
+use crate::structs::lsd::{Lsd, LSD_INTERVAL};
+use crate::structs::peers::generate_peer_id;
+use crate::structs::seeder::PORT;
+use crate::structs::torrent::TorrentStats;
+use crate::utils::trackers::{AnnounceEvent, QueryParams, TrackerList, TrackerResponse};
+use anyhow::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout};
+
+/// How long we wait between announces when the tracker doesn't say
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Announces are never more frequent than this, whatever the tracker says
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long we wait before trying again when no tracker answered
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the torrent is announced to the DHT, before the nodes forget about us
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How long the `stopped` announce may take, so that shutting down isn't held up by a dead tracker
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The peers, or the error, of an announce
+pub type AnnounceResult = Result<Vec<SocketAddr>, Error>;
+
+/// Announces a torrent to its trackers, to the DHT and on the local network in the background,
+/// for as long as we download or seed it.
+///
+/// The first announce has the `started` event, then the torrent is announced again every interval
+/// given by the tracker. [`Announcer::completed`] and [`Announcer::stop`] announce the matching event
+/// right away. The counters are read from the [`TorrentStats`] of the torrent for each announce.
+/// The DHT has no events: the torrent is announced to it every [`DHT_INTERVAL`], and on the local network
+/// every [`LSD_INTERVAL`]. The peers found on the local network are handed over as soon as they announce themselves.
+#[derive(Debug)]
+pub struct Announcer {
+    events: mpsc::UnboundedSender<AnnounceEvent>,
+
+    /// The result of each announce, for the download to connect to new peers
+    peers: Mutex<mpsc::Receiver<AnnounceResult>>,
+
+    task: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Announcer {
+    /// Start announcing the torrent `info_hash`.
+    pub fn start(
+        trackers: Arc<TrackerList>,
+        dht: Option<Arc<Dht>>,
+        lsd: Option<Arc<Lsd>>,
+        info_hash: [u8; 20],
+        stats: Arc<TorrentStats>,
+    ) -> Announcer {
+        let (events, events_receiver) = mpsc::unbounded_channel();
+        let (peers_sender, peers) = mpsc::channel(1);
+        let task = tokio::spawn(run(
+            trackers,
+            dht,
+            lsd,
+            info_hash,
+            stats,
+            events_receiver,
+            peers_sender,
+        ));
+        Announcer {
+            events,
+            peers: Mutex::new(peers),
+            task: std::sync::Mutex::new(Some(task)),
+        }
+    }
+
+    /// Wait for the result of the next announce.
+    /// While a result is waiting to be picked up, the results of later announces are dropped.
+    pub async fn next_peers(&self) -> Option<AnnounceResult> {
+        self.peers.lock().await.recv().await
+    }
+
+    /// Announce that the download is complete.
+    pub fn completed(&self) {
+        let _ = self.events.send(AnnounceEvent::Completed);
+    }
+
+    /// Announce that we are leaving the swarm, and wait for the announce to be sent.
+    pub async fn stop(&self) {
+        let _ = self.events.send(AnnounceEvent::Stopped);
+        let task = self.task.lock().expect("Locking announcer task").take();
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+}
+
+/// The announce loop: stops with a `stopped` announce when asked to, or when the [`Announcer`] is dropped.
+async fn run(
+    trackers: Arc<TrackerList>,
+    dht: Option<Arc<Dht>>,
+    lsd: Option<Arc<Lsd>>,
+    info_hash: [u8; 20],
+    stats: Arc<TorrentStats>,
+    mut events: mpsc::UnboundedReceiver<AnnounceEvent>,
+    peers: mpsc::Sender<AnnounceResult>,
+) {
+    let peer_id: String = generate_peer_id().iter().map(|b| *b as char).collect();
+    let query_params = |event: Option<AnnounceEvent>| QueryParams {
+        peer_id: peer_id.clone(),
+        port: PORT,
+        uploaded: stats.uploaded.load(Ordering::Relaxed),
+        downloaded: stats.downloaded.load(Ordering::Relaxed),
+        left: stats.left.load(Ordering::Relaxed),
+        compact: 1,
+        trackerid: None,
+        event,
+    };
+
+    let mut event = Some(AnnounceEvent::Started);
+    let mut next_dht_announce = Instant::now();
+    let mut next_lsd_announce = Instant::now();
+    let mut lan_peers = lsd.as_ref().map(|lsd| lsd.subscribe(info_hash));
+    // LAN peers waiting for the previous peers to be picked up
+    let mut pending = vec![];
+    'announce: loop {
+        if let Some(lsd) = lsd.as_ref().filter(|_| Instant::now() >= next_lsd_announce) {
+            next_lsd_announce = Instant::now() + LSD_INTERVAL;
+            if let Err(e) = lsd.announce(&info_hash).await {
+                eprintln!("LSD announce failed: {:?}", e);
+            }
+        }
+
+        let announce_trackers = async {
+            if trackers.is_empty() {
+                return None;
+            }
+            Some(trackers.announce(&query_params(event), &info_hash).await)
+        };
+        // Between two DHT announces, only the trackers are announced to
+        let announce_dht = async {
+            let dht = dht
+                .as_ref()
+                .filter(|_| Instant::now() >= next_dht_announce)?;
+            Some(dht.announce(info_hash, PORT).await)
+        };
+        let (tracker_result, dht_result) = tokio::join!(announce_trackers, announce_dht);
+
+        let mut addresses = vec![];
+        let mut last_error = None;
+        let mut wait = DHT_INTERVAL;
+        match tracker_result {
+            Some(Ok(response)) => {
+                event = None;
+                wait = announce_interval(&response);
+                addresses.extend(response.addresses());
+            }
+            Some(Err(e)) => {
+                // The event is sent again with the next announce
+                eprintln!("Announce failed: {:?}", e);
+                last_error = Some(e);
+                wait = RETRY_INTERVAL;
+            }
+            None => event = None,
+        }
+        match dht_result {
+            Some(Ok(dht_addresses)) => {
+                next_dht_announce = Instant::now() + DHT_INTERVAL;
+                addresses.extend(dht_addresses);
+            }
+            Some(Err(e)) => {
+                eprintln!("DHT announce failed: {:?}", e);
+                next_dht_announce = Instant::now() + RETRY_INTERVAL;
+                last_error = Some(e);
+            }
+            None => {}
+        }
+        if dht.is_some() {
+            wait = wait.min(next_dht_announce.saturating_duration_since(Instant::now()));
+        }
+        if lsd.is_some() {
+            wait = wait.min(next_lsd_announce.saturating_duration_since(Instant::now()));
+        }
+
+        let result = match last_error {
+            Some(e) if addresses.is_empty() => Err(e),
+            _ => Ok(addresses),
+        };
+        // Dropped if the previous peers weren't picked up, as once the download is over
+        let _ = peers.try_send(result);
+
+        let deadline = Instant::now() + wait;
+        loop {
+            tokio::select! {
+                _ = sleep_until(deadline.into()) => break,
+                received = events.recv() => match received {
+                    Some(AnnounceEvent::Stopped) | None => break 'announce,
+                    Some(received) => {
+                        event = Some(received);
+                        break;
+                    }
+                },
+                Some(address) = async { lan_peers.as_mut()?.recv().await }, if lan_peers.is_some() => {
+                    pending.push(address);
+                }
+                Ok(permit) = peers.reserve(), if !pending.is_empty() => {
+                    permit.send(Ok(std::mem::take(&mut pending)));
+                }
+            }
+        }
+    }
+
+    if let Some(lsd) = &lsd {
+        lsd.unsubscribe(&info_hash);
+    }
+    if trackers.is_empty() {
+        return;
+    }
+    let stopped = query_params(Some(AnnounceEvent::Stopped));
+    match timeout(STOP_TIMEOUT, trackers.announce(&stopped, &info_hash)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => eprintln!("Announcing stop failed: {:?}", e),
+        Err(_) => eprintln!("Announcing stop timed out"),
+    }
+}
+
+/// How long to wait before the next announce, as asked by the tracker.
+fn announce_interval(response: &TrackerResponse) -> Duration {
+    let interval = response
+        .interval
+        .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+    let min_interval = response
+        .min_interval
+        .map_or(MIN_INTERVAL, Duration::from_secs);
+    interval.max(min_interval).max(MIN_INTERVAL)
+}
@@ -0,0 +1,74 @@
+/// A set of piece indexes, encoded like the payload of a `bitfield` message:
+/// the high bit of the first byte corresponds to piece index 0.
+/// Spare bits at the end are set to zero.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// An empty bitfield for `len` pieces.
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Build a bitfield for `len` pieces from the raw bytes of a `bitfield` payload.
+    /// Missing bytes are treated as zeros and spare bits are cleared.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        let count = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..count].copy_from_slice(&bytes[..count]);
+        let spare_bits = bitfield.bytes.len() * 8 - len;
+        if let Some(last) = bitfield.bytes.last_mut() {
+            *last &= 0xFF << spare_bits;
+        }
+        bitfield
+    }
+
+    /// Number of pieces tracked by the bitfield.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indexes of the pieces which are not set.
+    pub fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| !self.has(*index))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
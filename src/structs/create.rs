@@ -0,0 +1,448 @@
+use crate::structs::extension::CLIENT_VERSION;
+use crate::structs::torrent::{Torrent, TorrentFile, TorrentInfo};
+use crate::utils::trackers::TrackerList;
+use anyhow::{anyhow, Context, Error};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Smallest piece length we pick: the size of a block
+const MIN_PIECE_LENGTH: i64 = 16 * 1024;
+
+/// Largest piece length we pick, however large the files are
+const MAX_PIECE_LENGTH: i64 = 16 * 1024 * 1024;
+
+/// The picked piece length is the smallest one which keeps the number of pieces under this
+const TARGET_PIECE_COUNT: i64 = 1500;
+
+/// What goes in a new torrent, besides its files.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CreateOptions {
+    /// Tiers of trackers: the first tracker goes to `announce`,
+    /// and the tiers go to `announce-list` when there are several trackers (BEP 12)
+    pub trackers: Vec<Vec<String>>,
+
+    /// Web seeds, in `url-list` (BEP 19)
+    pub web_seeds: Vec<String>,
+
+    pub comment: Option<String>,
+
+    /// The program which created the torrent, this client when not given
+    pub created_by: Option<String>,
+
+    /// Seconds since the Unix epoch, now when not given
+    pub creation_date: Option<i64>,
+
+    /// Whether peers must only be found through the trackers (BEP 27)
+    pub private: bool,
+
+    /// The tracker or site the torrent is made for, which changes the info hash
+    pub source: Option<String>,
+
+    /// Number of bytes in each piece: a power of two, of 16 KiB at least.
+    /// Picked from the total length of the files when not given
+    pub piece_length: Option<i32>,
+}
+
+impl Torrent {
+    /// Create the torrent of a file, or of every file under a directory.
+    ///
+    /// The files of a directory are sorted by path, and their pieces are hashed by as many threads
+    /// as there are CPUs. Write the result with [`Torrent::to_bytes`].
+    pub fn create(path: &Path, options: &CreateOptions) -> Result<Torrent, Error> {
+        // The torrent is named after the file or directory, which `.` or `..` don't give
+        let path =
+            &fs::canonicalize(path).with_context(|| format!("Reading {}", path.display()))?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(anyhow!("Invalid torrent name for {}", path.display()))?
+            .to_string();
+        let metadata = fs::metadata(path).with_context(|| format!("Reading {}", path.display()))?;
+        let files = if metadata.is_dir() {
+            list_files(path)?
+        } else {
+            vec![SourceFile {
+                path: path.to_path_buf(),
+                components: vec![name.clone()],
+                length: metadata.len() as i64,
+            }]
+        };
+
+        let total_length: i64 = files.iter().map(|file| file.length).sum();
+        if total_length == 0 {
+            return Err(anyhow!("Nothing to share in {}", path.display()));
+        }
+        let piece_length = match options.piece_length {
+            Some(piece_length)
+                if piece_length as i64 >= MIN_PIECE_LENGTH
+                    && (piece_length as u32).is_power_of_two() =>
+            {
+                piece_length
+            }
+            Some(piece_length) => {
+                return Err(anyhow!(
+                    "Invalid piece length {}: expected a power of two of 16 KiB at least",
+                    piece_length
+                ))
+            }
+            None => pick_piece_length(total_length),
+        };
+        let pieces = hash_pieces(&files, piece_length as i64)?;
+
+        let mut info = TorrentInfo {
+            length: (!metadata.is_dir()).then_some(total_length),
+            files: metadata.is_dir().then(|| {
+                files
+                    .into_iter()
+                    .map(|file| TorrentFile {
+                        length: file.length,
+                        path: file.components,
+                        attr: None,
+                    })
+                    .collect()
+            }),
+            name,
+            piece_length,
+            pieces: ByteBuf::from(pieces),
+            meta_version: None,
+            file_tree: None,
+            private: options.private.then_some(1),
+            source: options.source.clone(),
+            raw: vec![],
+            piece_layers: Default::default(),
+        };
+        // The info dictionary is written as it is bencoded here, which the info hash is computed from
+        info.raw = serde_bencode::to_bytes(&info).context("Bencoding the info section")?;
+
+        let tiers: Vec<Vec<String>> = options
+            .trackers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        let creation_date = options.creation_date.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64)
+        });
+        Ok(Torrent {
+            announce: tiers.iter().flatten().next().cloned().unwrap_or_default(),
+            announce_list: if tiers.iter().flatten().count() > 1 {
+                tiers.clone()
+            } else {
+                vec![]
+            },
+            comment: options.comment.clone(),
+            created_by: Some(
+                options
+                    .created_by
+                    .clone()
+                    .unwrap_or_else(|| CLIENT_VERSION.to_string()),
+            ),
+            creation_date: Some(creation_date),
+            url_list: options.web_seeds.clone(),
+            info,
+            stats: Default::default(),
+            strategy: Default::default(),
+            trackers: Arc::new(TrackerList::new(tiers)),
+            announcer: None,
+            wanted: None,
+            dht: None,
+            lsd: None,
+        })
+    }
+}
+
+/// Read tiers of trackers written as comma separated trackers, one string per tier.
+pub fn parse_tiers(tiers: &[String]) -> Vec<Vec<String>> {
+    tiers
+        .iter()
+        .map(|tier| {
+            tier.split(',')
+                .map(str::trim)
+                .filter(|tracker| !tracker.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .collect()
+}
+
+/// A file to put in a new torrent
+#[derive(Debug)]
+struct SourceFile {
+    /// Where the file is on disk
+    path: PathBuf,
+
+    /// Its path in the torrent, from the torrent directory
+    components: Vec<String>,
+
+    length: i64,
+}
+
+/// The files under a directory, subdirectories included, sorted by their path in the torrent.
+fn list_files(root: &Path) -> Result<Vec<SourceFile>, Error> {
+    let mut files = vec![];
+    let mut directories = vec![(root.to_path_buf(), vec![])];
+    while let Some((directory, components)) = directories.pop() {
+        let entries =
+            fs::read_dir(&directory).with_context(|| format!("Reading {}", directory.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or(anyhow!("Invalid file name {}", path.display()))?;
+            let mut components = components.clone();
+            components.push(name.to_string());
+            // Symbolic links are followed
+            let metadata =
+                fs::metadata(&path).with_context(|| format!("Reading {}", path.display()))?;
+            if metadata.is_dir() {
+                directories.push((path, components));
+            } else {
+                files.push(SourceFile {
+                    path,
+                    components,
+                    length: metadata.len() as i64,
+                });
+            }
+        }
+    }
+    files.sort_by(|a, b| a.components.cmp(&b.components));
+    Ok(files)
+}
+
+/// The smallest power of two which splits `total_length` bytes in [`TARGET_PIECE_COUNT`] pieces at most,
+/// between [`MIN_PIECE_LENGTH`] and [`MAX_PIECE_LENGTH`].
+fn pick_piece_length(total_length: i64) -> i32 {
+    let piece_length = (total_length as u64)
+        .div_ceil(TARGET_PIECE_COUNT as u64)
+        .next_power_of_two() as i64;
+    piece_length.clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH) as i32
+}
+
+/// The concatenated SHA-1 hashes of the pieces of the files, hashed in parallel.
+fn hash_pieces(files: &[SourceFile], piece_length: i64) -> Result<Vec<u8>, Error> {
+    let total_length: i64 = files.iter().map(|file| file.length).sum();
+    let piece_count = (total_length as u64).div_ceil(piece_length as u64) as usize;
+    let workers = thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(piece_count);
+
+    // Each thread takes the next piece nobody is hashing yet
+    let next_piece = AtomicUsize::new(0);
+    let mut hashes = vec![[0u8; 20]; piece_count];
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut reader = PieceReader::new(files);
+                    let mut hashed = vec![];
+                    loop {
+                        let index = next_piece.fetch_add(1, Ordering::Relaxed);
+                        if index >= piece_count {
+                            return Ok::<_, Error>(hashed);
+                        }
+                        let offset = index as i64 * piece_length;
+                        let length = piece_length.min(total_length - offset) as usize;
+                        let data = reader.read(offset, length).inspect_err(|_| {
+                            // The other threads stop at their next piece
+                            next_piece.store(piece_count, Ordering::Relaxed);
+                        })?;
+                        hashed.push((index, Sha1::digest(&data).into()));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            let hashed = handle
+                .join()
+                .map_err(|_| anyhow!("Hashing thread panicked"))??;
+            for (index, hash) in hashed {
+                hashes[index] = hash;
+            }
+        }
+        Ok::<_, Error>(())
+    })?;
+    Ok(hashes.concat())
+}
+
+/// Reads the bytes of pieces across the files of a new torrent, keeping the last file open.
+struct PieceReader<'a> {
+    files: &'a [SourceFile],
+
+    /// The last file read from, by index
+    open: Option<(usize, File)>,
+}
+
+impl<'a> PieceReader<'a> {
+    fn new(files: &'a [SourceFile]) -> PieceReader<'a> {
+        PieceReader { files, open: None }
+    }
+
+    /// Read `length` bytes at `offset` bytes from the start of the torrent data.
+    fn read(&mut self, offset: i64, length: usize) -> Result<Vec<u8>, Error> {
+        let end = offset + length as i64;
+        let mut data = vec![0u8; length];
+        let mut file_offset = 0;
+        for (index, file) in self.files.iter().enumerate() {
+            let (start, stop) = (offset.max(file_offset), end.min(file_offset + file.length));
+            if start < stop {
+                let handle = match &mut self.open {
+                    Some((open, handle)) if *open == index => handle,
+                    _ => {
+                        let handle = File::open(&file.path)
+                            .with_context(|| format!("Opening {}", file.path.display()))?;
+                        &mut self.open.insert((index, handle)).1
+                    }
+                };
+                handle.seek(SeekFrom::Start((start - file_offset) as u64))?;
+                handle
+                    .read_exact(&mut data[(start - offset) as usize..(stop - offset) as usize])
+                    .with_context(|| format!("Reading {}", file.path.display()))?;
+            }
+            file_offset += file.length;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Some bytes which don't repeat with the piece length, so that misplaced reads show
+    fn data(length: usize, seed: u8) -> Vec<u8> {
+        (0..length)
+            .map(|i| (i as u32).wrapping_mul(31).wrapping_add(seed as u32) as u8)
+            .collect()
+    }
+
+    /// Write files of the given lengths under `root`, and return them with their concatenated content.
+    fn write_files(root: &Path, lengths: &[usize]) -> (Vec<SourceFile>, Vec<u8>) {
+        let mut files = vec![];
+        let mut content = vec![];
+        for (index, length) in lengths.iter().enumerate() {
+            let bytes = data(*length, index as u8);
+            let path = root.join(format!("file{}", index));
+            fs::write(&path, &bytes).unwrap();
+            files.push(SourceFile {
+                path,
+                components: vec![format!("file{}", index)],
+                length: *length as i64,
+            });
+            content.extend(bytes);
+        }
+        (files, content)
+    }
+
+    #[test]
+    fn piece_length_keeps_the_piece_count_down() {
+        assert_eq!(pick_piece_length(1), MIN_PIECE_LENGTH as i32);
+        assert_eq!(
+            pick_piece_length(TARGET_PIECE_COUNT * MIN_PIECE_LENGTH),
+            MIN_PIECE_LENGTH as i32
+        );
+        // One byte more needs pieces twice as large
+        assert_eq!(
+            pick_piece_length(TARGET_PIECE_COUNT * MIN_PIECE_LENGTH + 1),
+            2 * MIN_PIECE_LENGTH as i32
+        );
+        assert_eq!(pick_piece_length(1 << 40), MAX_PIECE_LENGTH as i32);
+
+        for total_length in [100_000, 700 << 20, 3 << 30] {
+            let piece_length = pick_piece_length(total_length) as i64;
+            assert!((piece_length as u64).is_power_of_two());
+            assert!(
+                (total_length as u64).div_ceil(piece_length as u64) <= TARGET_PIECE_COUNT as u64
+            );
+        }
+    }
+
+    #[test]
+    fn pieces_are_read_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files, content) = write_files(dir.path(), &[10, 0, 25, 7]);
+        let mut reader = PieceReader::new(&files);
+
+        // Every range, including those starting or ending on a file boundary
+        for offset in 0..content.len() {
+            for length in 0..=content.len() - offset {
+                let read = reader.read(offset as i64, length).unwrap();
+                assert_eq!(
+                    read,
+                    content[offset..offset + length],
+                    "{}..+{}",
+                    offset,
+                    length
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pieces_are_hashed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let piece_length = MIN_PIECE_LENGTH as usize;
+        // The last piece is shorter, and most pieces span two files
+        let (files, content) = write_files(
+            dir.path(),
+            &[piece_length / 3, 2 * piece_length, piece_length + 5],
+        );
+
+        let pieces = hash_pieces(&files, piece_length as i64).unwrap();
+        let expected: Vec<u8> = content
+            .chunks(piece_length)
+            .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+            .collect();
+        assert_eq!(pieces.len(), 4 * 20);
+        assert_eq!(pieces, expected);
+    }
+
+    #[test]
+    fn created_torrent_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("shared");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.txt"), data(40_000, 1)).unwrap();
+        fs::write(root.join("sub").join("a.bin"), data(3, 2)).unwrap();
+        fs::write(root.join("a.txt"), data(20_000, 3)).unwrap();
+
+        let options = CreateOptions {
+            trackers: vec![vec!["http://tracker.example.com/announce".to_string()]],
+            comment: Some("test".to_string()),
+            creation_date: Some(1_700_000_000),
+            ..Default::default()
+        };
+        // `..` names the torrent after the directory it stands for
+        let torrent = Torrent::create(&root.join("sub").join(".."), &options).unwrap();
+        assert_eq!(torrent.info.name, "shared");
+        let paths: Vec<Vec<String>> = torrent
+            .info
+            .files
+            .iter()
+            .flatten()
+            .map(|file| file.path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![vec!["a.txt"], vec!["b.txt"], vec!["sub", "a.bin"]]
+        );
+        assert_eq!(torrent.info.piece_length, MIN_PIECE_LENGTH as i32);
+
+        let parsed = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.info.get_hash(), torrent.info.get_hash());
+        assert_eq!(parsed.info.pieces, torrent.info.pieces);
+        assert_eq!(parsed.announce, "http://tracker.example.com/announce");
+        assert_eq!(parsed.comment.as_deref(), Some("test"));
+    }
+}
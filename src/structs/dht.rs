@@ -0,0 +1,1095 @@
+use crate::structs::peers::{compact_address, to_compact, COMPACT_V4_LEN, COMPACT_V6_LEN};
+use crate::structs::seeder::PORT;
+use anyhow::{anyhow, Context, Error};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout};
+
+/// Number of nodes in a bucket of the routing table, and number of closest nodes a lookup looks for
+const K: usize = 8;
+
+/// Number of queries a lookup has in flight at once
+const ALPHA: usize = 3;
+
+/// How long we wait for a node to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Nodes we haven't heard from for this long are questionable, and may be replaced
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Nodes which didn't answer this many queries in a row are removed from the routing table
+const MAX_FAILURES: u32 = 2;
+
+/// How often the secret of the tokens changes. Tokens stay valid for one more rotation
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// How long the peers announced to us are kept
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// Most peers we give in a single `get_peers` answer, to stay within a UDP packet
+const MAX_VALUES: usize = 50;
+
+const ERROR_GENERIC: i64 = 201;
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// Node IDs and info hashes live in the same 160-bit space
+pub type NodeId = [u8; 20];
+
+/// Settings of the DHT node, from the `dht` table of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DhtConfig {
+    /// Address to listen on: nodes of the other IP version are ignored
+    pub address: IpAddr,
+
+    /// UDP port to listen on
+    pub port: u16,
+
+    /// `host:port` of the nodes we join the DHT through
+    pub bootstrap: Vec<String>,
+
+    /// Where the routing table is saved, to join the DHT through the same nodes next time
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: PORT,
+            bootstrap: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            state_path: Some(PathBuf::from("dht.dat")),
+        }
+    }
+}
+
+/// A node of the Mainline DHT, to find peers without a tracker.
+///
+/// Nodes are kept in a Kademlia routing table, by XOR distance to our own ID.
+/// Looking up an info hash asks the closest nodes we know for ever closer nodes,
+/// until the nodes closest to the info hash give us its peers, and a token to announce ourselves with.
+/// @link: https://www.bittorrent.org/beps/bep_0005.html
+#[derive(Debug)]
+pub struct Dht {
+    id: NodeId,
+    socket: UdpSocket,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+
+    /// Our queries waiting for an answer, by transaction ID
+    pending: Mutex<HashMap<u16, PendingQuery>>,
+    next_transaction: AtomicU16,
+
+    /// Peers announced to us, by info hash, with the time of their last announce
+    peers: Mutex<HashMap<NodeId, HashMap<SocketAddr, Instant>>>,
+
+    /// Tokens are the SHA-1 of the IP address of the node and of a secret
+    secrets: Mutex<TokenSecrets>,
+
+    /// Nodes of the routing table we saved last time, asked first when bootstrapping
+    saved_nodes: Vec<SocketAddr>,
+}
+
+impl Dht {
+    /// Start a DHT node, with the ID and the nodes saved at `config.state_path` if there are any.
+    /// The node answers queries until the process exits, [`Dht::bootstrap`] makes it join the DHT.
+    pub async fn bind(config: DhtConfig) -> Result<Arc<Dht>, Error> {
+        let state = config
+            .state_path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| serde_bencode::from_bytes::<DhtState>(&bytes).ok());
+        let id = state
+            .as_ref()
+            .and_then(|state| node_id(&state.id))
+            .unwrap_or_else(random);
+        let saved_nodes = state
+            .map(|state| {
+                let nodes = compact_nodes(&state.nodes, COMPACT_V4_LEN);
+                let nodes6 = compact_nodes(&state.nodes6, COMPACT_V6_LEN);
+                nodes
+                    .into_iter()
+                    .chain(nodes6)
+                    .map(|(_, address)| address)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let socket = UdpSocket::bind((config.address, config.port))
+            .await
+            .with_context(|| format!("Listening for DHT nodes on port {}", config.port))?;
+        let dht = Arc::new(Dht {
+            id,
+            socket,
+            config,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(random()),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(TokenSecrets {
+                current: random(),
+                previous: random(),
+            }),
+            saved_nodes,
+        });
+        tokio::spawn(dht.clone().receive());
+        tokio::spawn(dht.clone().maintain());
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Number of nodes in the routing table
+    pub fn node_count(&self) -> usize {
+        self.lock_table().len()
+    }
+
+    /// Join the DHT: ask the saved and the bootstrap nodes for the nodes closest to us.
+    /// Returns the number of nodes in the routing table.
+    pub async fn bootstrap(self: &Arc<Self>) -> Result<usize, Error> {
+        let mut seeds = self.saved_nodes.clone();
+        for node in &self.config.bootstrap {
+            match lookup_host(node.as_str()).await {
+                Ok(addresses) => {
+                    seeds.extend(addresses.filter(|address| self.is_reachable(address)))
+                }
+                Err(e) => eprintln!("Resolving DHT node {}: {:?}", node, e),
+            }
+        }
+
+        // The nodes which answer are added to the routing table, where the lookup starts from
+        let mut queries = JoinSet::new();
+        for address in seeds {
+            let dht = self.clone();
+            queries.spawn(async move { dht.find_node(address, dht.id).await });
+        }
+        while queries.join_next().await.is_some() {}
+        if self.node_count() == 0 {
+            return Err(anyhow!("No DHT node answered"));
+        }
+
+        self.lookup(self.id, false).await;
+        Ok(self.node_count())
+    }
+
+    /// Find peers of a torrent, without announcing ourselves.
+    pub async fn get_peers(
+        self: &Arc<Self>,
+        info_hash: [u8; 20],
+    ) -> Result<Vec<SocketAddr>, Error> {
+        self.ensure_bootstrapped().await?;
+        Ok(self.lookup(info_hash, true).await.peers)
+    }
+
+    /// Find peers of a torrent, and announce that we accept connections for it on `port`
+    /// to the closest nodes of the info hash.
+    pub async fn announce(
+        self: &Arc<Self>,
+        info_hash: [u8; 20],
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        self.ensure_bootstrapped().await?;
+        let lookup = self.lookup(info_hash, true).await;
+
+        let mut announces = JoinSet::new();
+        for (address, token) in lookup.closest {
+            let dht = self.clone();
+            announces.spawn(async move {
+                let arguments = Arguments {
+                    info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                    port: Some(port),
+                    token: Some(token),
+                    ..dht.arguments()
+                };
+                dht.query(address, "announce_peer", arguments).await
+            });
+        }
+        let mut announced = 0;
+        while let Some(result) = announces.join_next().await {
+            if matches!(result, Ok(Ok(_))) {
+                announced += 1;
+            }
+        }
+        if announced == 0 {
+            eprintln!("No DHT node accepted our announce");
+        }
+        Ok(lookup.peers)
+    }
+
+    /// Check that a node is alive, and get its ID.
+    pub async fn ping(&self, address: SocketAddr) -> Result<NodeId, Error> {
+        let values = self.query(address, "ping", self.arguments()).await?;
+        node_id(&values.id).ok_or(anyhow!("Invalid node ID"))
+    }
+
+    /// Save our ID and the routing table to `config.state_path`.
+    pub fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+        let mut state = DhtState {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        for node in self.lock_table().nodes() {
+            let nodes = match node.address {
+                SocketAddr::V4(_) => &mut state.nodes,
+                SocketAddr::V6(_) => &mut state.nodes6,
+            };
+            nodes.extend_from_slice(&node.id);
+            nodes.extend_from_slice(&to_compact(&node.address));
+        }
+        fs::write(path, serde_bencode::to_bytes(&state)?)
+            .with_context(|| format!("Saving DHT state to {}", path.display()))
+    }
+
+    async fn ensure_bootstrapped(self: &Arc<Self>) -> Result<(), Error> {
+        if self.node_count() == 0 {
+            self.bootstrap().await?;
+        }
+        Ok(())
+    }
+
+    /// Iterative lookup of the nodes closest to `target`, with `get_peers` or `find_node` queries.
+    ///
+    /// The [`ALPHA`] closest nodes not asked yet are queried, and the nodes they answer with become candidates,
+    /// until the [`K`] closest candidates which may still answer have all been queried.
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> Lookup {
+        // The candidates by distance to the target, so that the first ones are the closest
+        let mut candidates: BTreeMap<NodeId, Candidate> = self
+            .lock_table()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), Candidate::new(node.address)))
+            .collect();
+        let mut peers = HashSet::new();
+        let mut queries = JoinSet::new();
+
+        loop {
+            while queries.len() < ALPHA {
+                let next = candidates
+                    .iter_mut()
+                    .filter(|(_, candidate)| candidate.state != CandidateState::Failed)
+                    .take(K)
+                    .find(|(_, candidate)| candidate.state == CandidateState::Unqueried);
+                let Some((key, candidate)) = next else {
+                    break;
+                };
+                candidate.state = CandidateState::Queried;
+
+                let (key, address, dht) = (*key, candidate.address, self.clone());
+                queries.spawn(async move {
+                    let result = if get_peers {
+                        let arguments = Arguments {
+                            info_hash: Some(ByteBuf::from(target.to_vec())),
+                            ..dht.arguments()
+                        };
+                        dht.query(address, "get_peers", arguments).await
+                    } else {
+                        dht.find_node(address, target).await
+                    };
+                    (key, result)
+                });
+            }
+
+            let Some(joined) = queries.join_next().await else {
+                break;
+            };
+            let Ok((key, result)) = joined else {
+                continue;
+            };
+            let Some(candidate) = candidates.get_mut(&key) else {
+                continue;
+            };
+            match result {
+                Ok(values) => {
+                    candidate.state = CandidateState::Responded;
+                    candidate.token = values.token.clone();
+                    peers.extend(values.peers());
+                    for (id, address) in values.nodes() {
+                        if id != self.id && self.is_reachable(&address) {
+                            candidates
+                                .entry(distance(&id, &target))
+                                .or_insert_with(|| Candidate::new(address));
+                        }
+                    }
+                }
+                Err(_) => candidate.state = CandidateState::Failed,
+            }
+        }
+
+        let closest = candidates
+            .into_values()
+            .filter(|candidate| candidate.state == CandidateState::Responded)
+            .filter_map(|candidate| Some((candidate.address, candidate.token?)))
+            .take(K)
+            .collect();
+        Lookup {
+            peers: peers.into_iter().collect(),
+            closest,
+        }
+    }
+
+    async fn find_node(&self, address: SocketAddr, target: NodeId) -> Result<Values, Error> {
+        let arguments = Arguments {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..self.arguments()
+        };
+        self.query(address, "find_node", arguments).await
+    }
+
+    /// Send a query, and wait for its answer.
+    /// Nodes which answer are added to the routing table, the others get closer to being removed from it.
+    async fn query(
+        &self,
+        address: SocketAddr,
+        method: &str,
+        arguments: Arguments,
+    ) -> Result<Values, Error> {
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.lock_pending()
+            .insert(transaction, PendingQuery { address, sender });
+
+        let message = KrpcMessage {
+            t: ByteBuf::from(transaction.to_be_bytes().to_vec()),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(arguments),
+            ..Default::default()
+        };
+        let answer = match self.send(&message, address).await {
+            Ok(()) => timeout(QUERY_TIMEOUT, receiver)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Err(e) => {
+                self.lock_pending().remove(&transaction);
+                return Err(e);
+            }
+        };
+        self.lock_pending().remove(&transaction);
+
+        match answer {
+            Some(Ok(values)) => {
+                let id = node_id(&values.id).ok_or(anyhow!("Invalid node ID"))?;
+                self.lock_table().insert(id, address);
+                Ok(values)
+            }
+            Some(Err(e)) => Err(e),
+            None => {
+                self.lock_table().failed(address);
+                Err(anyhow!("DHT node {} is not responding", address))
+            }
+        }
+    }
+
+    async fn send(&self, message: &KrpcMessage, address: SocketAddr) -> Result<(), Error> {
+        let bytes = serde_bencode::to_bytes(message)?;
+        self.socket.send_to(&bytes, address).await?;
+        Ok(())
+    }
+
+    /// Read the messages sent to our socket: queries are answered, answers are handed to their query.
+    async fn receive(self: Arc<Self>) {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (length, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("DHT receive failed: {:?}", e);
+                    continue;
+                }
+            };
+            let Ok(message) = serde_bencode::from_bytes::<KrpcMessage>(&buffer[..length]) else {
+                continue;
+            };
+            match message.y.as_str() {
+                "q" => self.answer(message, from).await,
+                "r" | "e" => self.resolve(message, from),
+                _ => {}
+            }
+        }
+    }
+
+    async fn answer(&self, query: KrpcMessage, from: SocketAddr) {
+        let reply = match self.handle_query(&query, from) {
+            Ok(values) => KrpcMessage {
+                t: query.t,
+                y: "r".to_string(),
+                r: Some(values),
+                ..Default::default()
+            },
+            Err(error) => KrpcMessage {
+                t: query.t,
+                y: "e".to_string(),
+                e: Some(error),
+                ..Default::default()
+            },
+        };
+        if let Err(e) = self.send(&reply, from).await {
+            eprintln!("Answering DHT node {}: {:?}", from, e);
+        }
+    }
+
+    fn handle_query(&self, query: &KrpcMessage, from: SocketAddr) -> Result<Values, (i64, String)> {
+        let protocol_error = |message: &str| (ERROR_PROTOCOL, message.to_string());
+        let arguments = query
+            .a
+            .as_ref()
+            .ok_or_else(|| protocol_error("Missing arguments"))?;
+        let id = node_id(&arguments.id).ok_or_else(|| protocol_error("Invalid node ID"))?;
+        if self.is_reachable(&from) {
+            self.lock_table().seen(id, from);
+        }
+
+        let mut values = Values {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match query.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = arguments
+                    .target
+                    .as_ref()
+                    .and_then(|target| node_id(target))
+                    .ok_or_else(|| protocol_error("Invalid target"))?;
+                self.add_closest_nodes(&mut values, &target);
+            }
+            Some("get_peers") => {
+                let info_hash = arguments
+                    .info_hash
+                    .as_ref()
+                    .and_then(|info_hash| node_id(info_hash))
+                    .ok_or_else(|| protocol_error("Invalid info hash"))?;
+                values.token = Some(ByteBuf::from(self.token(from.ip(), false).to_vec()));
+                let peers = self.stored_peers(&info_hash);
+                if peers.is_empty() {
+                    self.add_closest_nodes(&mut values, &info_hash);
+                } else {
+                    values.values = Some(
+                        peers
+                            .iter()
+                            .map(|peer| ByteBuf::from(to_compact(peer)))
+                            .collect(),
+                    );
+                }
+            }
+            Some("announce_peer") => {
+                let info_hash = arguments
+                    .info_hash
+                    .as_ref()
+                    .and_then(|info_hash| node_id(info_hash))
+                    .ok_or_else(|| protocol_error("Invalid info hash"))?;
+                let token = arguments
+                    .token
+                    .as_ref()
+                    .ok_or_else(|| protocol_error("Missing token"))?;
+                if !self.is_valid_token(token, from.ip()) {
+                    return Err(protocol_error("Bad token"));
+                }
+                // With implied_port, the peer is on the port it sent the query from (behind a NAT)
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) if port != 0 => port,
+                    _ => return Err(protocol_error("Missing port")),
+                };
+                self.lock_peers()
+                    .entry(info_hash)
+                    .or_default()
+                    .insert(SocketAddr::new(from.ip(), port), Instant::now());
+            }
+            Some(_) => return Err((ERROR_METHOD_UNKNOWN, "Method Unknown".to_string())),
+            None => return Err((ERROR_GENERIC, "Missing method".to_string())),
+        }
+        Ok(values)
+    }
+
+    /// Hand an answer to the query waiting for it.
+    fn resolve(&self, message: KrpcMessage, from: SocketAddr) {
+        let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+            return;
+        };
+        let transaction = u16::from_be_bytes(transaction);
+        let mut pending = self.lock_pending();
+        // Only the node we asked may answer
+        if pending.get(&transaction).map(|query| query.address) != Some(from) {
+            return;
+        }
+        let Some(query) = pending.remove(&transaction) else {
+            return;
+        };
+        let result = match (message.r, message.e) {
+            (Some(values), _) if message.y == "r" => Ok(values),
+            (_, Some((code, text))) => Err(anyhow!("DHT error {}: {}", code, text)),
+            _ => Err(anyhow!("Invalid DHT answer")),
+        };
+        let _ = query.sender.send(result);
+    }
+
+    /// Every [`TOKEN_ROTATION`]: rotate the token secret, forget old peers,
+    /// refresh the buckets we haven't heard from, and save the routing table.
+    async fn maintain(self: Arc<Self>) {
+        let mut ticks = interval(TOKEN_ROTATION);
+        // The first tick completes right away
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            {
+                let mut secrets = self.lock_secrets();
+                secrets.previous = secrets.current;
+                secrets.current = random();
+            }
+            self.lock_peers().retain(|_, peers| {
+                peers.retain(|_, announced| announced.elapsed() < PEER_LIFETIME);
+                !peers.is_empty()
+            });
+
+            let stale_buckets = self.lock_table().stale_buckets();
+            for index in stale_buckets {
+                self.lookup(random_id_in_bucket(&self.id, index), false)
+                    .await;
+            }
+            if self.node_count() < K {
+                if let Err(e) = self.bootstrap().await {
+                    eprintln!("Joining the DHT: {:?}", e);
+                }
+            }
+            if let Err(e) = self.save() {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+
+    fn add_closest_nodes(&self, values: &mut Values, target: &NodeId) {
+        let mut nodes = vec![];
+        let mut nodes6 = vec![];
+        for node in self.lock_table().closest(target, K) {
+            let compact = match node.address {
+                SocketAddr::V4(_) => &mut nodes,
+                SocketAddr::V6(_) => &mut nodes6,
+            };
+            compact.extend_from_slice(&node.id);
+            compact.extend_from_slice(&to_compact(&node.address));
+        }
+        values.nodes = Some(ByteBuf::from(nodes));
+        if !nodes6.is_empty() {
+            values.nodes6 = Some(ByteBuf::from(nodes6));
+        }
+    }
+
+    fn stored_peers(&self, info_hash: &NodeId) -> Vec<SocketAddr> {
+        self.lock_peers()
+            .get(info_hash)
+            .map(|peers| peers.keys().take(MAX_VALUES).copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn token(&self, ip: IpAddr, previous: bool) -> [u8; 20] {
+        let secrets = self.lock_secrets();
+        let secret = if previous {
+            secrets.previous
+        } else {
+            secrets.current
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize().into()
+    }
+
+    fn is_valid_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == self.token(ip, false) || token == self.token(ip, true)
+    }
+
+    /// Nodes of the other IP version can't be reached from our socket
+    fn is_reachable(&self, address: &SocketAddr) -> bool {
+        address.is_ipv4() == self.config.address.is_ipv4()
+            && !address.ip().is_unspecified()
+            && address.port() != 0
+    }
+
+    fn arguments(&self) -> Arguments {
+        Arguments {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn lock_table(&self) -> MutexGuard<'_, RoutingTable> {
+        self.table.lock().expect("Locking DHT routing table")
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, HashMap<u16, PendingQuery>> {
+        self.pending.lock().expect("Locking DHT queries")
+    }
+
+    fn lock_peers(&self) -> MutexGuard<'_, HashMap<NodeId, HashMap<SocketAddr, Instant>>> {
+        self.peers.lock().expect("Locking DHT peers")
+    }
+
+    fn lock_secrets(&self) -> MutexGuard<'_, TokenSecrets> {
+        self.secrets.lock().expect("Locking DHT token secrets")
+    }
+}
+
+#[derive(Debug)]
+struct PendingQuery {
+    address: SocketAddr,
+    sender: oneshot::Sender<Result<Values, Error>>,
+}
+
+#[derive(Debug)]
+struct TokenSecrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+}
+
+/// What a lookup found: the peers of the info hash, and the closest nodes with the token they gave us
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    closest: Vec<(SocketAddr, ByteBuf)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CandidateState {
+    Unqueried,
+    Queried,
+    Responded,
+    Failed,
+}
+
+struct Candidate {
+    address: SocketAddr,
+    state: CandidateState,
+    token: Option<ByteBuf>,
+}
+
+impl Candidate {
+    fn new(address: SocketAddr) -> Candidate {
+        Candidate {
+            address,
+            state: CandidateState::Unqueried,
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    id: NodeId,
+    address: SocketAddr,
+
+    /// When the node last answered one of our queries, or sent us one
+    last_seen: Instant,
+
+    /// Number of our queries the node didn't answer, in a row
+    failures: u32,
+}
+
+impl Node {
+    fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < QUESTIONABLE_AFTER
+    }
+}
+
+/// The nodes we know, in buckets of at most [`K`] nodes.
+///
+/// The bucket of a node is the number of leading bits its ID shares with ours,
+/// so that we know many of the nodes close to us and a few of the ones far away.
+#[derive(Debug)]
+struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    fn new(own_id: NodeId) -> RoutingTable {
+        RoutingTable {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let shared_bits = leading_zeros(&distance(&self.own_id, id));
+        (shared_bits < 160).then_some(shared_bits)
+    }
+
+    /// Add a node which answered one of our queries.
+    /// When its bucket is full, it replaces the worst node which is no longer good, if there is one.
+    fn insert(&mut self, id: NodeId, address: SocketAddr) {
+        let Some(index) = self.bucket_index(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        let node = Node {
+            id,
+            address,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if let Some(known) = bucket.iter_mut().find(|known| known.id == id) {
+            *known = node;
+        } else if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(worst) = bucket
+            .iter_mut()
+            .filter(|known| !known.is_good())
+            .max_by_key(|known| (known.failures, Reverse(known.last_seen)))
+        {
+            *worst = node;
+        }
+    }
+
+    /// A node sent us a query.
+    /// Unknown nodes are only added when their bucket has room, since they haven't answered us yet.
+    fn seen(&mut self, id: NodeId, address: SocketAddr) {
+        let Some(index) = self.bucket_index(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(known) = bucket.iter_mut().find(|known| known.id == id) {
+            if known.address == address {
+                known.last_seen = Instant::now();
+            }
+        } else if bucket.len() < K {
+            bucket.push(Node {
+                id,
+                address,
+                last_seen: Instant::now(),
+                failures: 0,
+            });
+        }
+    }
+
+    /// A node didn't answer one of our queries.
+    fn failed(&mut self, address: SocketAddr) {
+        for bucket in &mut self.buckets {
+            bucket.retain_mut(|node| {
+                if node.address != address {
+                    return true;
+                }
+                node.failures += 1;
+                node.failures < MAX_FAILURES
+            });
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.nodes().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = Node> + '_ {
+        self.buckets.iter().flatten().copied()
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Buckets with nodes, but none we heard from lately
+    fn stale_buckets(&self) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| {
+                !bucket.is_empty()
+                    && bucket
+                        .iter()
+                        .all(|node| node.last_seen.elapsed() >= QUESTIONABLE_AFTER)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+/// A KRPC message: a query (`y` is `q`), an answer (`r`) or an error (`e`)
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct KrpcMessage {
+    /// Transaction ID, chosen by the querying node and echoed in the answer
+    t: ByteBuf,
+
+    y: String,
+
+    /// Method of a query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+
+    /// Arguments of a query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<Arguments>,
+
+    /// Values of an answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Values>,
+
+    /// Code and message of an error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Arguments {
+    id: ByteBuf,
+
+    /// The ID of the node we look for (`find_node`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+
+    /// The torrent we look for (`get_peers`) or announce (`announce_peer`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+
+    /// The token of the `get_peers` answer, which `announce_peer` must send back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+
+    /// When 1, the port is the one the query comes from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Values {
+    id: ByteBuf,
+
+    /// Compact IPv4 nodes: the node ID (20 bytes), then its IP address (4 bytes) and port (2 bytes)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+
+    /// Compact IPv6 nodes: the node ID (20 bytes), then its IP address (16 bytes) and port (2 bytes)
+    /// @link: https://www.bittorrent.org/beps/bep_0032.html
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes6: Option<ByteBuf>,
+
+    /// Compact peers of the torrent, IPv4 or IPv6
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+impl Values {
+    fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        let nodes = self
+            .nodes
+            .as_ref()
+            .map(|nodes| compact_nodes(nodes, COMPACT_V4_LEN))
+            .unwrap_or_default();
+        let nodes6 = self
+            .nodes6
+            .as_ref()
+            .map(|nodes| compact_nodes(nodes, COMPACT_V6_LEN))
+            .unwrap_or_default();
+        nodes.into_iter().chain(nodes6).collect()
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.values
+            .iter()
+            .flatten()
+            .filter_map(|peer| compact_address(peer))
+            .collect()
+    }
+}
+
+/// What we save of the node between runs
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DhtState {
+    id: ByteBuf,
+    #[serde(default)]
+    nodes: ByteBuf,
+    #[serde(default)]
+    nodes6: ByteBuf,
+}
+
+fn node_id(bytes: &[u8]) -> Option<NodeId> {
+    bytes.try_into().ok()
+}
+
+/// Read compact nodes: the node ID, then the compact address of `address_len` bytes.
+fn compact_nodes(bytes: &[u8], address_len: usize) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(20 + address_len)
+        .filter_map(|chunk| Some((node_id(&chunk[..20])?, compact_address(&chunk[20..])?)))
+        .collect()
+}
+
+/// XOR distance between two IDs, which compares as a big-endian number
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0u8; 20];
+    for (byte, (a, b)) in distance.iter_mut().zip(a.iter().zip(b)) {
+        *byte = a ^ b;
+    }
+    distance
+}
+
+fn leading_zeros(id: &NodeId) -> usize {
+    match id.iter().position(|byte| *byte != 0) {
+        Some(index) => index * 8 + id[index].leading_zeros() as usize,
+        None => 160,
+    }
+}
+
+/// A random ID which falls in bucket `index`: it shares its first `index` bits with our ID, and not the next one.
+fn random_id_in_bucket(own_id: &NodeId, index: usize) -> NodeId {
+    let mut id: NodeId = random();
+    for bit in 0..=index {
+        let (byte, mask) = (bit / 8, 0x80u8 >> (bit % 8));
+        let own_bit = own_id[byte] & mask;
+        let bit_value = if bit == index {
+            own_bit ^ mask
+        } else {
+            own_bit
+        };
+        id[byte] = (id[byte] & !mask) | bit_value;
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn loopback_config(bootstrap: Vec<String>) -> DhtConfig {
+        DhtConfig {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            bootstrap,
+            state_path: None,
+        }
+    }
+
+    #[test]
+    fn nodes_go_to_the_bucket_of_their_shared_prefix() {
+        let own_id: NodeId = random();
+        let mut table = RoutingTable::new(own_id);
+        for index in [0, 1, 7, 8, 80, 159] {
+            let id = random_id_in_bucket(&own_id, index);
+            assert_eq!(table.bucket_index(&id), Some(index));
+            table.insert(id, address(6881));
+            assert_eq!(table.buckets[index].len(), 1);
+        }
+        // Our own ID has no bucket
+        assert_eq!(table.bucket_index(&own_id), None);
+        table.insert(own_id, address(6881));
+        assert_eq!(table.len(), 6);
+    }
+
+    #[test]
+    fn full_bucket_only_replaces_nodes_which_are_not_good() {
+        let own_id: NodeId = random();
+        let mut table = RoutingTable::new(own_id);
+        let ids: Vec<NodeId> = (0..K).map(|_| random_id_in_bucket(&own_id, 3)).collect();
+        for (port, id) in (6881..).zip(&ids) {
+            table.insert(*id, address(port));
+        }
+
+        // Each bucket holds K nodes, whatever the other buckets hold
+        let near = random_id_in_bucket(&own_id, 4);
+        table.insert(near, address(7000));
+        assert_eq!(table.buckets[3].len(), K);
+        assert_eq!(table.buckets[4].len(), 1);
+
+        let newcomer = random_id_in_bucket(&own_id, 3);
+        table.insert(newcomer, address(7001));
+        assert!(table.nodes().all(|node| node.id != newcomer));
+
+        // A node which didn't answer is replaced by the next one which does
+        table.failed(address(6881));
+        table.insert(newcomer, address(7001));
+        assert_eq!(table.buckets[3].len(), K);
+        assert!(table.nodes().any(|node| node.id == newcomer));
+        assert!(table.nodes().all(|node| node.id != ids[0]));
+
+        // Unknown nodes which only queried us don't replace anyone
+        let querier = random_id_in_bucket(&own_id, 3);
+        table.failed(address(6882));
+        table.seen(querier, address(7002));
+        assert!(table.nodes().all(|node| node.id != querier));
+    }
+
+    #[test]
+    fn closest_nodes_are_sorted_by_distance() {
+        let own_id = [0u8; 20];
+        let mut table = RoutingTable::new(own_id);
+        let mut target = [0u8; 20];
+        target[0] = 0b1010_0000;
+        for (port, first_byte) in
+            (6881..).zip([0b1000_0000u8, 0b1010_0001, 0b0100_0000, 0b1011_0000])
+        {
+            let mut id = [0u8; 20];
+            id[0] = first_byte;
+            id[19] = 1;
+            table.insert(id, address(port));
+        }
+
+        let closest: Vec<u8> = table
+            .closest(&target, 3)
+            .iter()
+            .map(|node| node.id[0])
+            .collect();
+        assert_eq!(closest, vec![0b1010_0001, 0b1011_0000, 0b1000_0000]);
+    }
+
+    #[tokio::test]
+    async fn get_peers_finds_the_peer_announced_to_another_node() {
+        let first = Dht::bind(loopback_config(vec![])).await.unwrap();
+        let first_address = first.local_addr().unwrap();
+        let second = Dht::bind(loopback_config(vec![first_address.to_string()]))
+            .await
+            .unwrap();
+
+        assert_eq!(second.bootstrap().await.unwrap(), 1);
+        // The first node learnt of the second one from its query
+        assert_eq!(first.node_count(), 1);
+
+        let info_hash: [u8; 20] = random();
+        assert!(second.announce(info_hash, 7000).await.unwrap().is_empty());
+        assert_eq!(first.stored_peers(&info_hash), vec![address(7000)]);
+        assert_eq!(
+            second.get_peers(info_hash).await.unwrap(),
+            vec![address(7000)]
+        );
+    }
+}
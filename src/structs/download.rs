@@ -0,0 +1,787 @@
+use crate::structs::bitfield::Bitfield;
+use crate::structs::extension::{ExtensionRegistry, UT_PEX};
+use crate::structs::message::Message;
+use crate::structs::peers::Peer;
+use crate::structs::pex::{PeerExchange, Swarm, FLAG_REACHABLE, FLAG_SEED};
+use crate::structs::request::Request;
+use anyhow::{anyhow, Error};
+use rand::seq::IndexedRandom;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::sleep_until;
+
+pub const BLOCK_SIZE: i32 = 16 * 1024; // = 16384 bytes
+
+/// Requests sent to a peer before we know how fast it is
+const INITIAL_WINDOW: usize = 5;
+const MIN_WINDOW: usize = 2;
+const MAX_WINDOW: usize = 250;
+
+/// The window holds enough requests to keep a peer busy for this long
+const QUEUE_TIME: f64 = 3.0;
+
+/// How often the download rate of a peer is sampled
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an idle worker waits before checking the queue again
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of requests kept in flight with a peer.
+///
+/// The window grows with the measured download rate, so that high-latency peers
+/// always have enough requests queued to keep sending.
+#[derive(Debug)]
+pub struct RequestWindow {
+    size: usize,
+
+    /// Smoothed download rate, in bytes per second
+    rate: f64,
+    sample_start: Instant,
+    sample_bytes: usize,
+}
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        RequestWindow::new()
+    }
+}
+
+impl RequestWindow {
+    pub fn new() -> RequestWindow {
+        RequestWindow {
+            size: INITIAL_WINDOW,
+            rate: 0.0,
+            sample_start: Instant::now(),
+            sample_bytes: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Record a block received from the peer, and resize the window once per sample interval.
+    pub fn on_block(&mut self, length: usize) {
+        self.sample_bytes += length;
+        let elapsed = self.sample_start.elapsed();
+        if elapsed < RATE_SAMPLE_INTERVAL {
+            return;
+        }
+
+        let sample = self.sample_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            0.7 * self.rate + 0.3 * sample
+        };
+        self.size =
+            ((self.rate * QUEUE_TIME / BLOCK_SIZE as f64) as usize).clamp(MIN_WINDOW, MAX_WINDOW);
+        self.sample_start = Instant::now();
+        self.sample_bytes = 0;
+    }
+}
+
+/// Split a piece into the requests for its blocks.
+pub fn block_requests(piece_index: i32, piece_len: i32) -> VecDeque<Request> {
+    (0..piece_len)
+        .step_by(BLOCK_SIZE as usize)
+        .map(|begin| Request::new(piece_index, begin, BLOCK_SIZE.min(piece_len - begin)))
+        .collect()
+}
+
+/// Put requests the peer won't answer back at the front of the blocks to request.
+pub fn requeue(
+    pending_blocks: &mut VecDeque<Request>,
+    requests: impl IntoIterator<Item = Request>,
+) {
+    let mut requests: Vec<Request> = requests.into_iter().collect();
+    requests.sort_by_key(|request| (request.piece_index, request.begin));
+    for request in requests.into_iter().rev() {
+        pending_blocks.push_front(request);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingPiece {
+    pub piece_index: i32,
+    pub piece_len: i32,
+
+    /// Peers which sent data that didn't match the piece hash
+    pub failed_peers: Vec<SocketAddr>,
+}
+
+/// A downloaded piece, waiting to be verified.
+#[derive(Debug)]
+pub struct CompletedPiece {
+    pub piece: PendingPiece,
+
+    /// Peers which sent blocks of the piece: more than one in endgame mode
+    pub peers: Vec<SocketAddr>,
+    pub data: Vec<u8>,
+}
+
+/// A piece being downloaded, by a single worker or, in endgame mode, by several.
+#[derive(Debug)]
+struct ActivePiece {
+    piece: PendingPiece,
+    data: Vec<u8>,
+
+    /// Which blocks of the piece were received
+    received: Vec<bool>,
+    missing_bytes: usize,
+
+    /// Peers whose workers are downloading the piece
+    holders: Vec<SocketAddr>,
+
+    /// Peers which sent blocks of the piece
+    peers: Vec<SocketAddr>,
+}
+
+impl ActivePiece {
+    fn new(piece: PendingPiece, holder: SocketAddr) -> ActivePiece {
+        ActivePiece {
+            data: vec![0u8; piece.piece_len as usize],
+            received: vec![false; block_requests(piece.piece_index, piece.piece_len).len()],
+            missing_bytes: piece.piece_len as usize,
+            holders: vec![holder],
+            peers: vec![],
+            piece,
+        }
+    }
+
+    /// The requests for the blocks which weren't received yet.
+    fn missing_requests(&self) -> VecDeque<Request> {
+        block_requests(self.piece.piece_index, self.piece.piece_len)
+            .into_iter()
+            .zip(&self.received)
+            .filter(|(_, received)| !**received)
+            .map(|(request, _)| request)
+            .collect()
+    }
+}
+
+/// How the next piece to download is chosen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PickStrategy {
+    /// The piece the fewest peers have, so that rare pieces don't disappear from the swarm.
+    /// The first few pieces are picked at random instead, to get something to share quickly.
+    #[default]
+    RarestFirst,
+
+    /// Pieces in order, to play a file while it downloads.
+    Sequential,
+}
+
+/// Number of pieces picked at random before rarest-first kicks in
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// The pieces left to download, shared by the peer workers of a torrent.
+#[derive(Debug, Default)]
+pub struct PieceQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    pending: Vec<PendingPiece>,
+    strategy: PickStrategy,
+
+    /// Pieces handed to workers, by piece index
+    downloading: HashMap<i32, ActivePiece>,
+
+    /// Number of connected peers which have each piece
+    availability: Vec<u32>,
+
+    /// Pieces handed to a worker, and not verified nor given back yet
+    in_progress: usize,
+
+    /// Pieces verified since the download started
+    completed: usize,
+
+    /// Peers which sent too much corrupt data
+    banned: HashSet<SocketAddr>,
+}
+
+impl PieceQueue {
+    pub fn new(
+        piece_count: usize,
+        pieces: impl IntoIterator<Item = PendingPiece>,
+        strategy: PickStrategy,
+    ) -> PieceQueue {
+        PieceQueue {
+            state: Mutex::new(QueueState {
+                pending: pieces.into_iter().collect(),
+                strategy,
+                availability: vec![0; piece_count],
+                ..Default::default()
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().expect("Locking piece queue")
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.state().availability.len()
+    }
+
+    /// Take the next piece to ask `address` for, among the `pieces` it has.
+    pub fn next_for(&self, address: SocketAddr, pieces: &Bitfield) -> Option<PendingPiece> {
+        let mut state = self.state();
+        let candidates: Vec<usize> = state
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| {
+                pieces.has(piece.piece_index as usize) && !piece.failed_peers.contains(&address)
+            })
+            .map(|(position, _)| position)
+            .collect();
+
+        let piece_index = |position: &usize| state.pending[*position].piece_index as usize;
+        let position = match state.strategy {
+            PickStrategy::Sequential => candidates.iter().copied().min_by_key(piece_index)?,
+            PickStrategy::RarestFirst if state.completed < RANDOM_FIRST_PIECES => {
+                *candidates.choose(&mut rand::rng())?
+            }
+            PickStrategy::RarestFirst => {
+                let rarity = |position: &usize| state.availability[piece_index(position)];
+                let rarest = candidates.iter().map(rarity).min()?;
+                // Break ties at random, so that peers don't all ask for the same pieces
+                let rarest: Vec<usize> = candidates
+                    .iter()
+                    .copied()
+                    .filter(|position| rarity(position) == rarest)
+                    .collect();
+                *rarest.choose(&mut rand::rng())?
+            }
+        };
+
+        let piece = state.pending.swap_remove(position);
+        state.in_progress += 1;
+        state
+            .downloading
+            .insert(piece.piece_index, ActivePiece::new(piece.clone(), address));
+        Some(piece)
+    }
+
+    /// In endgame mode, once every piece left is being downloaded, join the download of a piece
+    /// `address` has: returns the piece index and the requests for the blocks still missing.
+    ///
+    /// The same blocks are then requested from several peers, and the first copy to arrive wins.
+    pub fn endgame_for(
+        &self,
+        address: SocketAddr,
+        pieces: &Bitfield,
+    ) -> Option<(i32, VecDeque<Request>)> {
+        let mut state = self.state();
+        if !state.pending.is_empty() {
+            return None;
+        }
+        let active = state
+            .downloading
+            .values_mut()
+            .filter(|active| {
+                pieces.has(active.piece.piece_index as usize)
+                    && !active.holders.contains(&address)
+                    && !active.piece.failed_peers.contains(&address)
+            })
+            .min_by_key(|active| active.holders.len())?;
+        active.holders.push(address);
+        Some((active.piece.piece_index, active.missing_requests()))
+    }
+
+    /// Whether the worker of `address` is still downloading the piece.
+    pub fn holds(&self, piece_index: i32, address: SocketAddr) -> bool {
+        self.state()
+            .downloading
+            .get(&piece_index)
+            .is_some_and(|active| active.holders.contains(&address))
+    }
+
+    /// Whether the block of a request from the worker of `address` is still missing.
+    /// It isn't once another peer sent it first, in endgame mode.
+    pub fn is_needed(&self, address: SocketAddr, request: &Request) -> bool {
+        let state = self.state();
+        let Some(active) = state.downloading.get(&request.piece_index) else {
+            return false;
+        };
+        let block_index = (request.begin / BLOCK_SIZE) as usize;
+        active.holders.contains(&address) && active.received.get(block_index) == Some(&false)
+    }
+
+    /// Store a block sent by `address`. Returns the piece once all of its blocks arrived.
+    pub fn store_block(
+        &self,
+        address: SocketAddr,
+        piece_index: i32,
+        begin: i32,
+        block: &[u8],
+    ) -> Option<CompletedPiece> {
+        let mut state = self.state();
+        let active = state.downloading.get_mut(&piece_index)?;
+        if begin < 0 || begin % BLOCK_SIZE != 0 {
+            return None;
+        }
+        let block_index = (begin / BLOCK_SIZE) as usize;
+        let expected_len = BLOCK_SIZE.min(active.piece.piece_len - begin) as usize;
+        if active.received.get(block_index) != Some(&false) || block.len() != expected_len {
+            // A duplicate, from endgame mode
+            return None;
+        }
+
+        let begin = begin as usize;
+        active.data[begin..begin + block.len()].copy_from_slice(block);
+        active.received[block_index] = true;
+        active.missing_bytes -= block.len();
+        if !active.peers.contains(&address) {
+            active.peers.push(address);
+        }
+        if active.missing_bytes > 0 {
+            return None;
+        }
+
+        let active = state.downloading.remove(&piece_index)?;
+        Some(CompletedPiece {
+            piece: active.piece,
+            peers: active.peers,
+            data: active.data,
+        })
+    }
+
+    /// Stop downloading a piece with the worker of `address`.
+    /// The piece goes back to the queue once no worker is downloading it anymore.
+    pub fn release(&self, piece_index: i32, address: SocketAddr) {
+        let mut state = self.state();
+        let Some(active) = state.downloading.get_mut(&piece_index) else {
+            return;
+        };
+        active.holders.retain(|holder| *holder != address);
+        if !active.holders.is_empty() {
+            return;
+        }
+        if let Some(active) = state.downloading.remove(&piece_index) {
+            state.in_progress -= 1;
+            state.pending.push(active.piece);
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Count the pieces of a peer which joined the download.
+    pub fn add_peer(&self, pieces: &Bitfield) {
+        self.update_peer(&Bitfield::new(0), pieces);
+    }
+
+    /// Stop counting the pieces of a peer which left the download.
+    pub fn remove_peer(&self, pieces: &Bitfield) {
+        self.update_peer(pieces, &Bitfield::new(0));
+    }
+
+    /// Update the availability of pieces when a peer goes from having `before` to `after`.
+    pub fn update_peer(&self, before: &Bitfield, after: &Bitfield) {
+        let mut state = self.state();
+        for (piece_index, count) in state.availability.iter_mut().enumerate() {
+            match (before.has(piece_index), after.has(piece_index)) {
+                (false, true) => *count += 1,
+                (true, false) => *count = count.saturating_sub(1),
+                _ => {}
+            }
+        }
+        drop(state);
+        // Workers waiting for a piece their peer has may now have one
+        self.notify.notify_waiters();
+    }
+
+    /// Give back a downloaded piece which must be downloaded again.
+    pub fn push(&self, piece: PendingPiece) {
+        let mut state = self.state();
+        state.in_progress -= 1;
+        state.pending.push(piece);
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Mark a taken piece as done.
+    pub fn complete(&self) {
+        let mut state = self.state();
+        state.in_progress -= 1;
+        state.completed += 1;
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    pub fn ban(&self, address: SocketAddr) {
+        self.state().banned.insert(address);
+    }
+
+    pub fn is_banned(&self, address: SocketAddr) -> bool {
+        self.state().banned.contains(&address)
+    }
+
+    /// Whether a worker with nothing to do should keep waiting:
+    /// pieces still being downloaded may fail their hash check and come back to the queue.
+    pub fn may_have_work(&self) -> bool {
+        self.state().in_progress > 0
+    }
+
+    /// Whether every piece was downloaded and verified.
+    pub fn is_done(&self) -> bool {
+        let state = self.state();
+        state.pending.is_empty() && state.in_progress == 0
+    }
+
+    async fn wait(&self) {
+        let _ = tokio::time::timeout(IDLE_INTERVAL, self.notify.notified()).await;
+    }
+}
+
+/// Download pieces from the queue with a single peer, until the queue is done or the peer fails.
+///
+/// Requests are pipelined: up to the size of the [`RequestWindow`] are kept in flight,
+/// across several pieces if needed, and blocks are matched to their request as they arrive.
+/// Once there is no piece left to take, the worker helps with the pieces of other workers
+/// (endgame mode), and cancels its requests for the blocks which arrive from other peers first.
+/// Unfinished pieces go back to the queue when the worker stops.
+///
+/// Extension messages go through an [`ExtensionRegistry`]. With a `swarm`, the worker also exchanges peers
+/// with its peer (`ut_pex`), when the peer supports it.
+pub async fn download_from_peer(
+    mut peer: Peer,
+    queue: Arc<PieceQueue>,
+    completed: mpsc::Sender<CompletedPiece>,
+    swarm: Option<Arc<Swarm>>,
+) {
+    let address = peer.address;
+    // Indexes of the pieces the worker is downloading
+    let mut active: Vec<i32> = vec![];
+    let mut pending_blocks: VecDeque<Request> = VecDeque::new();
+    let mut in_flight: Vec<Request> = vec![];
+    let mut window = RequestWindow::new();
+
+    // The pieces the peer has, as counted in the availability of the queue
+    let piece_count = queue.piece_count();
+    let mut pieces = peer.pieces(piece_count);
+    queue.add_peer(&pieces);
+
+    let mut extensions = ExtensionRegistry::new();
+    match swarm {
+        Some(swarm) => {
+            // We connected to the peer, so others can too
+            let seed = if pieces.is_complete() { FLAG_SEED } else { 0 };
+            let exchange = PeerExchange::new(swarm, address, FLAG_REACHABLE | seed);
+            extensions.register(Box::new(exchange));
+        }
+        // Without a swarm, the handshake tells the peer not to send peer exchange messages
+        None => extensions.disable(UT_PEX),
+    }
+    // The handshake of the peer may have been read before the worker started (magnet links)
+    if let Some(handshake) = peer.remote_extensions() {
+        extensions.on_handshake(handshake);
+    }
+
+    let result: Result<(), Error> = async {
+        if peer.supports_extensions() {
+            peer.send(extensions.handshake_message(&address)?).await?;
+        }
+        if !peer.state().am_interested {
+            peer.send(Message::Interested).await?;
+        }
+        loop {
+            if queue.is_banned(address) {
+                return Err(anyhow!("Peer is banned"));
+            }
+
+            for message in extensions.poll_messages()? {
+                peer.send(message).await?;
+            }
+
+            // Drop the blocks which another peer sent first, in endgame mode
+            active.retain(|piece_index| queue.holds(*piece_index, address));
+            pending_blocks.retain(|request| queue.is_needed(address, request));
+            let (needed, unneeded): (Vec<Request>, Vec<Request>) = in_flight
+                .iter()
+                .partition(|request| queue.is_needed(address, request));
+            in_flight = needed;
+            for request in unneeded {
+                peer.send(Message::Cancel(request)).await?;
+            }
+
+            // Fill the window, taking new pieces from the queue when needed
+            let choked = peer.state().peer_choking;
+            // The peer may accept fewer outstanding requests than our window holds
+            let max_in_flight = extensions
+                .remote()
+                .and_then(|handshake| usize::try_from(handshake.reqq?).ok())
+                .filter(|reqq| *reqq > 0)
+                .map_or(window.size(), |reqq| window.size().min(reqq));
+            while !choked && in_flight.len() < max_in_flight {
+                if pending_blocks.is_empty() {
+                    if let Some(piece) = queue.next_for(address, &pieces) {
+                        pending_blocks.extend(block_requests(piece.piece_index, piece.piece_len));
+                        active.push(piece.piece_index);
+                    } else if let Some((piece_index, requests)) =
+                        queue.endgame_for(address, &pieces)
+                    {
+                        pending_blocks.extend(requests);
+                        active.push(piece_index);
+                    } else {
+                        break;
+                    }
+                }
+                let Some(request) = pending_blocks.pop_front() else {
+                    break;
+                };
+                peer.send(Message::Request(request)).await?;
+                in_flight.push(request);
+            }
+
+            let idle = in_flight.is_empty() && !choked;
+            if in_flight.is_empty() {
+                // Keep reading until the peer unchokes us, unless there is nothing left to ask for
+                if choked && active.is_empty() && queue.is_done() {
+                    return Ok(());
+                }
+                if !choked && !queue.may_have_work() {
+                    return Ok(());
+                }
+            }
+            let next_poll = extensions.next_poll();
+            let message = tokio::select! {
+                message = peer.read() => message?,
+                // Nothing to ask the peer for right now: wait for pieces to come back to the queue,
+                // or for the peer to announce new pieces
+                _ = queue.wait(), if idle => continue,
+                // The extension messages are sent at the top of the loop
+                _ = sleep_until(next_poll.unwrap_or_else(Instant::now).into()), if next_poll.is_some() => continue,
+            };
+
+            let (index, begin, block) = match message {
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                } => (index, begin, block),
+                // The peer drops our outstanding requests when it chokes us
+                Message::Choke => {
+                    requeue(&mut pending_blocks, in_flight.drain(..));
+                    continue;
+                }
+                Message::RejectRequest(request) => {
+                    if let Some(position) = in_flight.iter().position(|r| *r == request) {
+                        requeue(&mut pending_blocks, [in_flight.swap_remove(position)]);
+                    }
+                    continue;
+                }
+                Message::Have { .. }
+                | Message::Bitfield(_)
+                | Message::HaveAll
+                | Message::HaveNone => {
+                    let current = peer.pieces(piece_count);
+                    queue.update_peer(&pieces, &current);
+                    pieces = current;
+                    continue;
+                }
+                Message::Extension { id, payload } => {
+                    if let Err(e) = extensions.on_message(id, &payload) {
+                        eprintln!("Invalid extension message from {}: {:?}", address, e);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            let Some(position) = in_flight.iter().position(|request| {
+                request.piece_index == index
+                    && request.begin == begin
+                    && request.length as usize == block.len()
+            }) else {
+                // A block we didn't ask for (or not anymore)
+                continue;
+            };
+            in_flight.swap_remove(position);
+            window.on_block(block.len());
+
+            if let Some(done) = queue.store_block(address, index, begin, &block) {
+                active.retain(|piece_index| *piece_index != index);
+                completed
+                    .send(done)
+                    .await
+                    .map_err(|_| anyhow!("Download is over"))?;
+            }
+        }
+    }
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Stopped downloading from {}: {:?}", address, e);
+    }
+    queue.remove_peer(&pieces);
+    for piece_index in active {
+        queue.release(piece_index, address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_COUNT: usize = 6;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn bitfield(pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(PIECE_COUNT);
+        pieces
+            .iter()
+            .for_each(|piece_index| bitfield.set(*piece_index));
+        bitfield
+    }
+
+    fn pending(piece_index: i32, piece_len: i32) -> PendingPiece {
+        PendingPiece {
+            piece_index,
+            piece_len,
+            failed_peers: vec![],
+        }
+    }
+
+    /// A queue of every piece, each of two blocks, past the random first pieces
+    fn queue(strategy: PickStrategy) -> PieceQueue {
+        let pieces = (0..PIECE_COUNT as i32).map(|index| pending(index, 2 * BLOCK_SIZE));
+        let queue = PieceQueue::new(PIECE_COUNT, pieces, strategy);
+        queue.state().completed = RANDOM_FIRST_PIECES;
+        queue
+    }
+
+    #[test]
+    fn rarest_pieces_are_picked_first() {
+        let queue = queue(PickStrategy::RarestFirst);
+        // Piece 4 is the rarest, then piece 2, then the others
+        queue.add_peer(&bitfield(&[0, 1, 2, 3, 4, 5]));
+        queue.add_peer(&bitfield(&[0, 1, 2, 3, 5]));
+        queue.add_peer(&bitfield(&[0, 1, 3, 5]));
+        let all = bitfield(&[0, 1, 2, 3, 4, 5]);
+
+        let first = queue.next_for(peer(1), &all).unwrap();
+        assert_eq!(first.piece_index, 4);
+        let second = queue.next_for(peer(1), &all).unwrap();
+        assert_eq!(second.piece_index, 2);
+
+        // Only the pieces the peer has are picked, however rare the others are
+        let third = queue.next_for(peer(2), &bitfield(&[1])).unwrap();
+        assert_eq!(third.piece_index, 1);
+        assert!(queue.next_for(peer(2), &bitfield(&[1, 2, 4])).is_none());
+    }
+
+    #[test]
+    fn availability_follows_peers() {
+        let queue = queue(PickStrategy::RarestFirst);
+        let everything = bitfield(&[0, 1, 2, 3, 4, 5]);
+        queue.add_peer(&everything);
+        queue.add_peer(&everything);
+        queue.add_peer(&bitfield(&[0, 1, 2, 3, 4]));
+        // The peer announces piece 5 as well, then another peer with piece 5 leaves
+        queue.update_peer(&bitfield(&[0, 1, 2, 3, 4]), &everything);
+        queue.remove_peer(&bitfield(&[5]));
+        assert_eq!(queue.state().availability, vec![3, 3, 3, 3, 3, 2]);
+        assert_eq!(queue.next_for(peer(1), &everything).unwrap().piece_index, 5);
+    }
+
+    #[test]
+    fn pieces_are_not_given_back_to_peers_which_corrupted_them() {
+        let queue = PieceQueue::new(
+            PIECE_COUNT,
+            [PendingPiece {
+                failed_peers: vec![peer(1)],
+                ..pending(0, BLOCK_SIZE)
+            }],
+            PickStrategy::RarestFirst,
+        );
+        let all = bitfield(&[0, 1, 2, 3, 4, 5]);
+        assert!(queue.next_for(peer(1), &all).is_none());
+        assert_eq!(queue.next_for(peer(2), &all).unwrap().piece_index, 0);
+    }
+
+    #[test]
+    fn sequential_picks_pieces_in_order() {
+        let queue = queue(PickStrategy::Sequential);
+        queue.add_peer(&bitfield(&[5]));
+        let all = bitfield(&[0, 1, 2, 3, 4, 5]);
+        let picked: Vec<i32> = std::iter::from_fn(|| queue.next_for(peer(1), &all))
+            .map(|piece| piece.piece_index)
+            .collect();
+        assert_eq!(picked, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn endgame_shares_the_last_pieces() {
+        let queue = PieceQueue::new(
+            PIECE_COUNT,
+            [pending(0, 2 * BLOCK_SIZE), pending(1, BLOCK_SIZE + 10)],
+            PickStrategy::Sequential,
+        );
+        let all = bitfield(&[0, 1, 2, 3, 4, 5]);
+        // Not in endgame mode while pieces are still pending
+        assert!(queue.endgame_for(peer(2), &all).is_none());
+        let first = queue.next_for(peer(1), &all).unwrap();
+        assert!(queue.endgame_for(peer(2), &all).is_none());
+        let second = queue.next_for(peer(2), &all).unwrap();
+        assert_eq!((first.piece_index, second.piece_index), (0, 1));
+
+        // A peer doesn't join a piece it already downloads, nor a piece it doesn't have
+        assert!(queue.endgame_for(peer(3), &bitfield(&[2])).is_none());
+        let block = vec![1u8; BLOCK_SIZE as usize];
+        assert!(queue.store_block(peer(1), 0, 0, &block).is_none());
+        let (piece_index, requests) = queue.endgame_for(peer(3), &bitfield(&[0])).unwrap();
+        assert_eq!(piece_index, 0);
+        // Only the block still missing is requested again
+        assert_eq!(
+            requests,
+            VecDeque::from([Request::new(0, BLOCK_SIZE, BLOCK_SIZE)])
+        );
+        assert!(queue.holds(0, peer(3)));
+        assert!(queue.is_needed(peer(1), &requests[0]));
+        assert!(queue.is_needed(peer(3), &requests[0]));
+
+        // The first copy completes the piece, the late one is dropped
+        let completed = queue.store_block(peer(3), 0, BLOCK_SIZE, &block).unwrap();
+        assert_eq!(completed.peers, vec![peer(1), peer(3)]);
+        assert_eq!(completed.data.len(), 2 * BLOCK_SIZE as usize);
+        assert!(!queue.is_needed(peer(1), &requests[0]));
+        assert!(queue.store_block(peer(1), 0, BLOCK_SIZE, &block).is_none());
+        queue.complete();
+    }
+
+    #[test]
+    fn endgame_pieces_go_back_once_every_holder_left() {
+        let queue = PieceQueue::new(
+            PIECE_COUNT,
+            [pending(3, BLOCK_SIZE)],
+            PickStrategy::RarestFirst,
+        );
+        let all = bitfield(&[0, 1, 2, 3, 4, 5]);
+        queue.next_for(peer(1), &all).unwrap();
+        queue.endgame_for(peer(2), &all).unwrap();
+        // Every piece left is already held by the peer
+        assert!(queue.endgame_for(peer(2), &all).is_none());
+
+        queue.release(3, peer(1));
+        assert!(queue.may_have_work());
+        assert!(queue.holds(3, peer(2)));
+        queue.release(3, peer(2));
+        assert!(!queue.holds(3, peer(2)));
+        assert!(!queue.may_have_work());
+        assert!(!queue.is_done());
+        assert_eq!(queue.next_for(peer(1), &all).unwrap().piece_index, 3);
+    }
+}
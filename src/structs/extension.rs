@@ -1,18 +1,227 @@
+use crate::structs::message::Message;
+use crate::structs::metadata::MetadataExtension;
+use crate::structs::peers::to_compact;
+use crate::structs::seeder::{MAX_QUEUED_REQUESTS, PORT};
+use anyhow::{anyhow, Error};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Instant;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Name of the metadata exchange extension (BEP 9)
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Name of the peer exchange extension (BEP 11)
+pub const UT_PEX: &str = "ut_pex";
+
+/// Our message ID for `ut_metadata` messages, as advertised in our handshake
+pub const UT_METADATA_ID: u8 = 1;
+
+/// The client name and version we send in handshakes
+pub const CLIENT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// The extension handshake: the extensions supported, and a few facts about the client.
+/// Every key is optional, and the keys we don't know about are ignored.
+/// @link: https://www.bittorrent.org/beps/bep_0010.html
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Extension {
-    #[serde(rename = "m")]
+    #[serde(rename = "m", default)]
     pub inner: InnerDictionnary,
-    pub metadata_size: u8,
-    // pub reqq: u8,
-    // pub v: ByteBuf,
+
+    /// Size of the info dictionary, in bytes: only sent by peers which have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+
+    /// Number of outstanding requests the client accepts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+
+    /// Port the client accepts connections on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+
+    /// The IP address of the receiver, as the sender sees it, in its compact form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct InnerDictionnary {
-    pub ut_metadata: u8,
-    // pub ut_pex: u8,
+impl Extension {
+    /// The client of the sender, when it says.
+    pub fn client(&self) -> Option<String> {
+        self.v
+            .as_ref()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+}
+
+/// The message IDs of the extensions a peer supports, by name: 0 (or a missing key) means unsupported
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InnerDictionnary(pub BTreeMap<String, i64>);
+
+impl InnerDictionnary {
+    /// The message ID of an extension, `None` when it isn't supported.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        let id = *self.0.get(name)?;
+        u8::try_from(id).ok().filter(|id| *id != 0)
+    }
+}
+
+/// An extension of the extension protocol, handling its messages for a single connection.
+pub trait ExtensionHandler: Send {
+    /// The name the extension is advertised with, in the `m` dictionary of handshakes
+    fn name(&self) -> &'static str;
+
+    /// The peer sent its extension handshake. `supported` is false when the peer doesn't support the extension.
+    fn on_handshake(&mut self, _handshake: &Extension, _supported: bool) {}
+
+    /// Handle a message of the extension from the peer.
+    fn on_message(&mut self, payload: &[u8]) -> Result<(), Error>;
+
+    /// The payload of the next message to send to the peer, if there is one.
+    /// Only called once the peer said it supports the extension.
+    fn poll_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    /// When [`ExtensionHandler::poll_message`] should be called again, for extensions sending messages on their own.
+    fn next_poll(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// The extensions of a connection.
+///
+/// Our message ID for each extension is its position in the registry, starting at 1:
+/// the peer sends its messages with that ID. Our messages are sent with the ID the peer gave
+/// to the extension in its handshake. `ut_metadata` always comes first, with [`UT_METADATA_ID`],
+/// since [`crate::structs::peers::Peer::request_metadata`] reads its answers outside of the registry.
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+
+    /// Extensions advertised with the ID 0: we don't want the peer to send them
+    disabled: Vec<&'static str>,
+
+    /// The handshake of the peer, once received
+    remote: Option<Extension>,
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        ExtensionRegistry::new()
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry {
+            handlers: vec![],
+            disabled: vec![],
+            remote: None,
+        };
+        registry.register(Box::new(MetadataExtension::default()));
+        registry
+    }
+
+    /// Add an extension, returning the ID the peer will send its messages with.
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8 {
+        self.handlers.push(handler);
+        self.handlers.len() as u8
+    }
+
+    /// Tell the peer we don't want the messages of an extension (`ut_pex` for private torrents).
+    pub fn disable(&mut self, name: &'static str) {
+        self.disabled.push(name);
+    }
+
+    /// The handshake of the peer, once received.
+    pub fn remote(&self) -> Option<&Extension> {
+        self.remote.as_ref()
+    }
+
+    /// Our handshake, for the peer at `address`.
+    pub fn handshake(&self, address: &SocketAddr) -> Extension {
+        let mut inner = InnerDictionnary::default();
+        for name in &self.disabled {
+            inner.0.insert(name.to_string(), 0);
+        }
+        for (index, handler) in self.handlers.iter().enumerate() {
+            inner.0.insert(handler.name().to_string(), index as i64 + 1);
+        }
+        let mut yourip = to_compact(address);
+        yourip.truncate(yourip.len() - 2);
+        Extension {
+            inner,
+            metadata_size: None,
+            v: Some(ByteBuf::from(CLIENT_VERSION)),
+            reqq: Some(MAX_QUEUED_REQUESTS as i64),
+            p: Some(PORT),
+            yourip: Some(ByteBuf::from(yourip)),
+        }
+    }
+
+    /// Our handshake as a message, for the peer at `address`.
+    pub fn handshake_message(&self, address: &SocketAddr) -> Result<Message, Error> {
+        Ok(Message::Extension {
+            id: 0,
+            payload: serde_bencode::to_bytes(&self.handshake(address))?,
+        })
+    }
+
+    /// Keep the handshake of the peer, and tell every extension whether the peer supports it.
+    pub fn on_handshake(&mut self, handshake: Extension) {
+        for handler in &mut self.handlers {
+            let supported = handshake.inner.id(handler.name()).is_some();
+            handler.on_handshake(&handshake, supported);
+        }
+        self.remote = Some(handshake);
+    }
+
+    /// Hand an extension message from the peer to its extension, by the ID we advertised for it.
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) -> Result<(), Error> {
+        if id == 0 {
+            let handshake: Extension = serde_bencode::from_bytes(payload)?;
+            self.on_handshake(handshake);
+            return Ok(());
+        }
+        let handler = self
+            .handlers
+            .get_mut(id as usize - 1)
+            .ok_or(anyhow!("Unknown extension message ID {}", id))?;
+        handler.on_message(payload)
+    }
+
+    /// The messages the extensions have for the peer, with the IDs the peer gave them.
+    pub fn poll_messages(&mut self) -> Result<Vec<Message>, Error> {
+        let Some(remote) = &self.remote else {
+            return Ok(vec![]);
+        };
+        let mut messages = vec![];
+        for handler in &mut self.handlers {
+            let Some(id) = remote.inner.id(handler.name()) else {
+                continue;
+            };
+            if let Some(payload) = handler.poll_message()? {
+                messages.push(Message::Extension { id, payload });
+            }
+        }
+        Ok(messages)
+    }
+
+    /// When [`ExtensionRegistry::poll_messages`] should be called next, if an extension is waiting to send a message.
+    pub fn next_poll(&self) -> Option<Instant> {
+        let remote = self.remote.as_ref()?;
+        self.handlers
+            .iter()
+            .filter(|handler| remote.inner.id(handler.name()).is_some())
+            .filter_map(|handler| handler.next_poll())
+            .min()
+    }
 }
 
 #[allow(dead_code)]
@@ -27,18 +236,19 @@ pub struct MetadataPayload {
     /// The corresponding ExtensionMessageType
     pub msg_type: u8,
     /// Indicates which part of the metadata this message refers to
-    pub piece: u8,
+    pub piece: u32,
 }
 
-/// The 16kiB Metadata info
+/// The header of a metadata message: `data` messages are followed by the 16kiB piece of metadata
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MetadataInfo {
     /// The corresponding ExtensionMessageType
     pub msg_type: u8,
 
     /// Indicates which part of the metadata this message refers to
-    pub piece: u8,
+    pub piece: u32,
 
-    /// Metadata total size
-    pub total_size: i32,
+    /// Metadata total size, only in `data` messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<i64>,
 }
@@ -50,6 +50,6 @@ impl Handshake {
         }
     }
     pub fn peer_id_string(&self) -> String {
-        hex::encode(&self.peer_id)
+        hex::encode(self.peer_id)
     }
 }
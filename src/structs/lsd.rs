@@ -0,0 +1,344 @@
+use anyhow::{Context, Error};
+use rand::random;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// The multicast group LSD announces are sent to
+pub const LSD_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// How often each torrent is announced on the local network
+pub const LSD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// We answer the announces of other peers for a torrent at most once per minute, so that
+/// the peers of a large network don't keep answering each other
+const MIN_ANSWER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Local Service Discovery: find the peers of a torrent on the local network,
+/// through announces sent to a multicast group.
+///
+/// An announce gives the port the peer accepts connections on, and the info hashes of its torrents:
+/// ```text
+/// BT-SEARCH * HTTP/1.1\r\n
+/// Host: 239.192.152.143:6771\r\n
+/// Port: <port>\r\n
+/// Infohash: <info hash in hexadecimal>\r\n
+/// cookie: <to recognize our own announces>\r\n
+/// \r\n
+/// \r\n
+/// ```
+/// @link: https://www.bittorrent.org/beps/bep_0014.html
+#[derive(Debug)]
+pub struct Lsd {
+    socket: UdpSocket,
+
+    /// The port we accept peers on
+    port: u16,
+
+    /// Sent with our announces, which come back to us through the multicast loop
+    cookie: String,
+
+    /// Where the peers found for each torrent go
+    torrents: Mutex<HashMap<[u8; 20], mpsc::UnboundedSender<SocketAddr>>>,
+
+    /// When we last answered the announce of another peer, for each torrent
+    answered: Mutex<HashMap<[u8; 20], Instant>>,
+}
+
+impl Lsd {
+    /// Join the LSD multicast group, announcing that we accept peers on `port`.
+    /// Other clients on the same host may listen to the group too.
+    pub async fn bind(port: u16) -> Result<Arc<Lsd>, Error> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_ADDRESS.port())).into())
+            .with_context(|| {
+                format!("Listening for LSD announces on port {}", LSD_ADDRESS.port())
+            })?;
+        socket
+            .join_multicast_v4(LSD_ADDRESS.ip(), &Ipv4Addr::UNSPECIFIED)
+            .context("Joining the LSD multicast group")?;
+        // Other clients on this host are on the local network too
+        socket.set_multicast_loop_v4(true)?;
+
+        let lsd = Arc::new(Lsd {
+            socket: UdpSocket::from_std(socket.into())?,
+            port,
+            cookie: format!("{:016x}", random::<u64>()),
+            torrents: Mutex::new(HashMap::new()),
+            answered: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(lsd.clone().receive());
+        Ok(lsd)
+    }
+
+    /// Get the peers announced on the local network for a torrent, until [`Lsd::unsubscribe`].
+    pub fn subscribe(&self, info_hash: [u8; 20]) -> mpsc::UnboundedReceiver<SocketAddr> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.lock_torrents().insert(info_hash, sender);
+        receiver
+    }
+
+    pub fn unsubscribe(&self, info_hash: &[u8; 20]) {
+        self.lock_torrents().remove(info_hash);
+    }
+
+    /// Announce a torrent on the local network.
+    pub async fn announce(&self, info_hash: &[u8; 20]) -> Result<(), Error> {
+        let message = LsdAnnounce {
+            port: self.port,
+            info_hashes: vec![*info_hash],
+            cookie: Some(self.cookie.clone()),
+        }
+        .to_string();
+        self.socket
+            .send_to(message.as_bytes(), LSD_ADDRESS)
+            .await
+            .context("Sending LSD announce")?;
+        Ok(())
+    }
+
+    /// Read the announces of the other peers, and hand them to the torrents they are for.
+    async fn receive(self: Arc<Self>) {
+        let mut buffer = vec![0u8; 1500];
+        loop {
+            let (length, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("LSD receive failed: {:?}", e);
+                    continue;
+                }
+            };
+            let Some(announce) = LsdAnnounce::parse(&buffer[..length]) else {
+                continue;
+            };
+            if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+
+            let address = SocketAddr::new(from.ip(), announce.port);
+            for info_hash in announce.info_hashes {
+                let Some(torrent) = self.lock_torrents().get(&info_hash).cloned() else {
+                    continue;
+                };
+                let _ = torrent.send(address);
+
+                // Announce back, so that the new peer finds us without waiting for our next announce
+                if self.should_answer(&info_hash) {
+                    if let Err(e) = self.announce(&info_hash).await {
+                        eprintln!("{:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether to answer an announce for a torrent now, given [`MIN_ANSWER_INTERVAL`].
+    fn should_answer(&self, info_hash: &[u8; 20]) -> bool {
+        let mut answered = self.lock_answered();
+        if answered
+            .get(info_hash)
+            .is_some_and(|answered| answered.elapsed() < MIN_ANSWER_INTERVAL)
+        {
+            return false;
+        }
+        answered.insert(*info_hash, Instant::now());
+        true
+    }
+
+    fn lock_torrents(
+        &self,
+    ) -> MutexGuard<'_, HashMap<[u8; 20], mpsc::UnboundedSender<SocketAddr>>> {
+        self.torrents.lock().expect("Locking LSD torrents")
+    }
+
+    fn lock_answered(&self) -> MutexGuard<'_, HashMap<[u8; 20], Instant>> {
+        self.answered.lock().expect("Locking LSD answers")
+    }
+}
+
+/// An announce sent to, or received from, the local network
+#[derive(Debug, PartialEq, Eq)]
+struct LsdAnnounce {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    /// Parse an announce: header names are case insensitive, and `Infohash` may be repeated.
+    fn parse(datagram: &[u8]) -> Option<LsdAnnounce> {
+        let text = std::str::from_utf8(datagram).ok()?;
+        let mut lines = text.split("\r\n");
+        if !lines.next()?.starts_with("BT-SEARCH * HTTP/1.") {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                // Info hashes we can't read are skipped, the others of the announce are still valid
+                "infohash" => {
+                    if let Some(info_hash) = hex::decode(value)
+                        .ok()
+                        .and_then(|bytes| <[u8; 20]>::try_from(bytes).ok())
+                    {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Some(LsdAnnounce {
+            port: port.filter(|port| *port != 0)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+impl fmt::Display for LsdAnnounce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            LSD_ADDRESS, self.port
+        )?;
+        for info_hash in &self.info_hashes {
+            write!(f, "Infohash: {}\r\n", hex::encode(info_hash))?;
+        }
+        if let Some(cookie) = &self.cookie {
+            write!(f, "cookie: {}\r\n", cookie)?;
+        }
+        write!(f, "\r\n\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    const INFO_HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    fn info_hash() -> [u8; 20] {
+        hex::decode(INFO_HASH).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn announce_is_formatted_as_bep_14() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![info_hash()],
+            cookie: Some("0123456789abcdef".to_string()),
+        };
+        assert_eq!(
+            announce.to_string(),
+            format!(
+                "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+                 Infohash: {}\r\ncookie: 0123456789abcdef\r\n\r\n\r\n",
+                INFO_HASH
+            )
+        );
+        assert_eq!(
+            LsdAnnounce::parse(announce.to_string().as_bytes()),
+            Some(announce)
+        );
+    }
+
+    #[test]
+    fn headers_are_case_insensitive_and_info_hashes_repeated() {
+        let other = "0".repeat(40);
+        let datagram = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: 239.192.152.143:6771\r\nport:  51413 \r\n\
+             infohash: {}\r\nINFOHASH: {}\r\n\r\n\r\n",
+            INFO_HASH, other
+        );
+        let announce = LsdAnnounce::parse(datagram.as_bytes()).unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes, vec![info_hash(), [0; 20]]);
+        assert_eq!(announce.cookie, None);
+    }
+
+    #[test]
+    fn invalid_info_hashes_are_skipped() {
+        for wrong in [
+            "c12fe1c06bba254a9dc9f519b335aa7c1367a8",
+            "not an info hash",
+            "",
+        ] {
+            let datagram = format!(
+                "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: {}\r\nInfohash: {}\r\n\r\n\r\n",
+                wrong, INFO_HASH
+            );
+            let announce = LsdAnnounce::parse(datagram.as_bytes()).unwrap();
+            assert_eq!(announce.info_hashes, vec![info_hash()], "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn announce_without_a_valid_port_is_rejected() {
+        for port in ["", "0", "65536", "-1", "6881a"] {
+            let datagram = format!(
+                "BT-SEARCH * HTTP/1.1\r\nPort: {}\r\nInfohash: {}\r\n\r\n\r\n",
+                port, INFO_HASH
+            );
+            assert_eq!(LsdAnnounce::parse(datagram.as_bytes()), None, "{}", port);
+        }
+        let datagram = format!(
+            "BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\n\r\n",
+            INFO_HASH
+        );
+        assert_eq!(LsdAnnounce::parse(datagram.as_bytes()), None);
+    }
+
+    #[test]
+    fn other_messages_are_rejected() {
+        let datagram = format!(
+            "NOTIFY * HTTP/1.1\r\nPort: 6881\r\nInfohash: {}\r\n\r\n",
+            INFO_HASH
+        );
+        assert_eq!(LsdAnnounce::parse(datagram.as_bytes()), None);
+        assert_eq!(LsdAnnounce::parse(&[0xff, 0xfe]), None);
+    }
+
+    #[tokio::test]
+    async fn announce_reaches_the_other_clients_of_the_host() {
+        let listener = Lsd::bind(6881).await.unwrap();
+        let announcer = Lsd::bind(6882).await.unwrap();
+        let mut peers = listener.subscribe(info_hash());
+        // Our own announces come back through the multicast loop, and are ignored
+        let mut own = announcer.subscribe(info_hash());
+
+        announcer.announce(&info_hash()).await.unwrap();
+        let peer = timeout(Duration::from_secs(5), peers.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.port(), 6882);
+
+        // The listener announces back, at most once per minute
+        let peer = timeout(Duration::from_secs(5), own.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.port(), 6881);
+        assert!(!listener.should_answer(&info_hash()));
+    }
+}
@@ -1,54 +1,429 @@
+use crate::structs::torrent::Torrent;
 use anyhow::{anyhow, Context};
 use hex::FromHex;
 use reqwest::Url;
-use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 
 #[derive(Debug, Clone)]
 pub struct MagnetLink {
+    /// The info hash used with peers, trackers and the DHT: the v1 info hash,
+    /// or the v2 info hash truncated to 20 bytes when the link only has that one
     pub info_hash: [u8; 20],
+
+    /// The v2 info hash (BEP 52), for v2 and hybrid torrents
+    pub info_hash_v2: Option<[u8; 32]>,
+
     pub name: Option<String>,
-    pub tracker_url: String,
+
+    /// Every `tr` parameter, in order
+    pub trackers: Vec<String>,
+
+    /// Peers to connect to directly (`x.pe`): `host:port`, `ipv4:port` or `[ipv6]:port`
+    pub peers: Vec<String>,
+
+    /// Web seeds (`ws`, BEP 19)
+    pub web_seeds: Vec<String>,
+
+    /// Indexes of the files to download (`so`, BEP 53), all of them when empty
+    pub select_only: Vec<RangeInclusive<usize>>,
+
+    /// Whether the link has a v1 info hash: v2-only links don't
+    has_v1: bool,
 }
 
-const XT_PREFIX: &'static str = "urn:btih:";
+const XT_PREFIX: &str = "urn:btih:";
+
+/// Prefix of v2 info hashes: `urn:btmh:` then the multihash of the info hash, in hexadecimal
+const XT_V2_PREFIX: &str = "urn:btmh:";
+
+/// The multihash header of a SHA-256 hash: the hash function (0x12), then the length (0x20)
+const SHA256_MULTIHASH: [u8; 2] = [0x12, 0x20];
 
+/// The digits of base32 (RFC 4648), in which info hashes may be written instead of hexadecimal
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Parse magnet links, as described in BEP 9 and its extensions.
+/// @link: https://www.bittorrent.org/beps/bep_0009.html
 impl FromStr for MagnetLink {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let url = Url::from_str(s)?;
+        let url = Url::from_str(s).context("Parsing magnet link")?;
         if url.scheme() != "magnet" {
-            return Err(anyhow::anyhow!("Invalid scheme !"));
+            return Err(anyhow!("Invalid scheme {}, expected magnet", url.scheme()));
+        }
+
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut magnet_link = MagnetLink {
+            info_hash: [0; 20],
+            info_hash_v2: None,
+            name: None,
+            trackers: vec![],
+            peers: vec![],
+            web_seeds: vec![],
+            select_only: vec![],
+            has_v1: false,
+        };
+        // Trackers, peers and web seeds may be given several times, so they are collected in order
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(XT_PREFIX) {
+                        info_hash = Some(parse_btih(hash)?);
+                    } else if let Some(multihash) = value.strip_prefix(XT_V2_PREFIX) {
+                        info_hash_v2 = Some(parse_btmh(multihash)?);
+                    }
+                    // Other kinds of exact topics (urn:sha1:, ...) aren't about BitTorrent
+                }
+                "dn" => magnet_link.name = Some(value.into_owned()),
+                "tr" => {
+                    let tracker = Url::from_str(&value)
+                        .with_context(|| format!("Invalid tracker {}", value))?;
+                    magnet_link.trackers.push(tracker.to_string());
+                }
+                "x.pe" => {
+                    let (host, port) = value
+                        .rsplit_once(':')
+                        .ok_or(anyhow!("Invalid peer {}, expected host:port", value))?;
+                    port.parse::<u16>()
+                        .with_context(|| format!("Invalid port in peer {}", value))?;
+                    if host.is_empty() {
+                        return Err(anyhow!("Invalid peer {}, expected host:port", value));
+                    }
+                    magnet_link.peers.push(value.into_owned());
+                }
+                "ws" => {
+                    let web_seed = Url::from_str(&value)
+                        .with_context(|| format!("Invalid web seed {}", value))?;
+                    magnet_link.web_seeds.push(web_seed.to_string());
+                }
+                "so" => magnet_link.select_only = parse_select_only(&value)?,
+                _ => {}
+            }
+        }
+
+        // v2-only links are found on the wire with their truncated v2 hash
+        magnet_link.has_v1 = info_hash.is_some();
+        magnet_link.info_hash = match (info_hash, info_hash_v2) {
+            (Some(info_hash), _) => info_hash,
+            (None, Some(info_hash_v2)) => truncate_v2(&info_hash_v2),
+            (None, None) => {
+                return Err(anyhow!(
+                    "Missing xt parameter with a urn:btih: or urn:btmh: info hash"
+                ))
+            }
+        };
+        magnet_link.info_hash_v2 = info_hash_v2;
+        Ok(magnet_link)
+    }
+}
+
+/// Write the link back, to share it: `MagnetLink::from_str` reads it as it was.
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params = vec![];
+        if self.has_v1 {
+            params.push(format!("xt={}{}", XT_PREFIX, hex::encode(self.info_hash)));
+        }
+        if let Some(info_hash_v2) = &self.info_hash_v2 {
+            params.push(format!(
+                "xt={}{}{}",
+                XT_V2_PREFIX,
+                hex::encode(SHA256_MULTIHASH),
+                hex::encode(info_hash_v2)
+            ));
         }
 
-        let query_pairs = url.query_pairs().collect::<HashMap<_, _>>();
-        let xt = query_pairs.get("xt");
+        let mut pairs: Vec<(&str, String)> = vec![];
+        if let Some(name) = &self.name {
+            pairs.push(("dn", name.clone()));
+        }
+        pairs.extend(self.trackers.iter().map(|tracker| ("tr", tracker.clone())));
+        pairs.extend(
+            self.web_seeds
+                .iter()
+                .map(|web_seed| ("ws", web_seed.clone())),
+        );
+        pairs.extend(self.peers.iter().map(|peer| ("x.pe", peer.clone())));
+        if !self.select_only.is_empty() {
+            let ranges: Vec<String> = self
+                .select_only
+                .iter()
+                .map(|range| {
+                    if range.start() == range.end() {
+                        range.start().to_string()
+                    } else {
+                        format!("{}-{}", range.start(), range.end())
+                    }
+                })
+                .collect();
+            pairs.push(("so", ranges.join(",")));
+        }
+        if !pairs.is_empty() {
+            params.push(serde_urlencoded::to_string(&pairs).map_err(|_| fmt::Error)?);
+        }
 
-        let xt = xt
-            .unwrap()
-            .strip_prefix(XT_PREFIX)
-            .ok_or(anyhow!("Invalid xt value"))?;
-        let bytes = Vec::from_hex(xt)?;
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
 
-        let info_hash: [u8; 20] = bytes
-            .as_slice()
-            .try_into()
-            .context("Info hash must be 20 bytes")?;
+impl MagnetLink {
+    /// The magnet link of a torrent, with its name, trackers and web seeds.
+    pub fn from_torrent(torrent: &Torrent) -> MagnetLink {
+        MagnetLink {
+            info_hash: torrent.info.get_hash(),
+            info_hash_v2: torrent.info.info_hash_v2(),
+            name: Some(torrent.info.name.clone()),
+            trackers: torrent.tiers().into_iter().flatten().collect(),
+            peers: vec![],
+            web_seeds: torrent.url_list.clone(),
+            select_only: vec![],
+            has_v1: torrent.info.has_v1(),
+        }
+    }
 
-        let name = query_pairs.get("dn").map(|s| s.to_string());
+    /// The trackers of the link, each in its own tier so that they are tried in order.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.trackers
+            .iter()
+            .map(|tracker| vec![tracker.clone()])
+            .collect()
+    }
 
-        let tracker_url = query_pairs
-            .get("tr")
-            .map(|s| Url::from_str(&s))
-            .transpose()?;
+    /// Whether the file at `index` in the torrent is to be downloaded, according to `so`.
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|range| range.contains(&index))
+    }
+}
+
+/// Read a v1 info hash: 40 hexadecimal characters, or 32 base32 characters.
+fn parse_btih(hash: &str) -> Result<[u8; 20], anyhow::Error> {
+    let bytes = match hash.len() {
+        40 => Vec::from_hex(hash).with_context(|| format!("Invalid info hash {}", hash))?,
+        32 => base32_decode(hash).ok_or(anyhow!("Invalid base32 info hash {}", hash))?,
+        length => {
+            return Err(anyhow!(
+                "Invalid info hash {}: expected 40 hexadecimal or 32 base32 characters, got {}",
+                hash,
+                length
+            ))
+        }
+    };
+    bytes
+        .as_slice()
+        .try_into()
+        .context("Info hash must be 20 bytes")
+}
+
+/// Read a v2 info hash: the hexadecimal multihash of a SHA-256 hash.
+fn parse_btmh(multihash: &str) -> Result<[u8; 32], anyhow::Error> {
+    let bytes =
+        Vec::from_hex(multihash).with_context(|| format!("Invalid v2 info hash {}", multihash))?;
+    let hash = bytes.strip_prefix(&SHA256_MULTIHASH).ok_or(anyhow!(
+        "Unsupported multihash {}, expected SHA-256",
+        multihash
+    ))?;
+    hash.try_into().context("v2 info hash must be 32 bytes")
+}
 
-        let tracker_url = tracker_url.map_or(String::new(), |s| s.to_string());
+/// The v2 info hash as it is used wherever 20 bytes are expected: its first 20 bytes (BEP 52).
+pub fn truncate_v2(info_hash_v2: &[u8; 32]) -> [u8; 20] {
+    info_hash_v2[..20].try_into().expect("20 bytes out of 32")
+}
 
-        Ok(MagnetLink {
-            info_hash,
-            name,
-            tracker_url,
+/// Read the `so` parameter: comma separated file indexes, and ranges of them such as `4-6`.
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>, anyhow::Error> {
+    value
+        .split(',')
+        .map(|item| {
+            let invalid = || anyhow!("Invalid file selection {}", value);
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let start: usize = start.trim().parse().map_err(|_| invalid())?;
+            let end: usize = end.trim().parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
         })
+        .collect()
+}
+
+/// Decode base32 without padding, in either case.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|digit| *digit == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    const INFO_HASH_BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+    const INFO_HASH_V2: &str = "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb";
+
+    fn info_hash() -> [u8; 20] {
+        <[u8; 20]>::from_hex(INFO_HASH).unwrap()
+    }
+
+    fn info_hash_v2() -> [u8; 32] {
+        <[u8; 32]>::from_hex(INFO_HASH_V2).unwrap()
+    }
+
+    fn parse(link: &str) -> MagnetLink {
+        link.parse().unwrap()
+    }
+
+    #[test]
+    fn hexadecimal_and_base32_info_hashes() {
+        let link = parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=sample&tr=http%3A%2F%2Ftracker.example.com%2Fannounce",
+            INFO_HASH
+        ));
+        assert_eq!(link.info_hash, info_hash());
+        assert_eq!(link.info_hash_v2, None);
+        assert_eq!(link.name.as_deref(), Some("sample"));
+        assert_eq!(link.trackers, vec!["http://tracker.example.com/announce"]);
+
+        let link = parse(&format!("magnet:?xt=urn:btih:{}", INFO_HASH_BASE32));
+        assert_eq!(link.info_hash, info_hash());
+        let link = parse(&format!(
+            "magnet:?xt=urn:btih:{}",
+            INFO_HASH_BASE32.to_lowercase()
+        ));
+        assert_eq!(link.info_hash, info_hash());
+    }
+
+    #[test]
+    fn v2_links_use_the_truncated_v2_info_hash() {
+        let link = parse(&format!("magnet:?xt=urn:btmh:1220{}", INFO_HASH_V2));
+        assert_eq!(link.info_hash_v2, Some(info_hash_v2()));
+        assert_eq!(link.info_hash, truncate_v2(&info_hash_v2()));
+        assert!(!link.has_v1);
+    }
+
+    #[test]
+    fn hybrid_links_keep_both_info_hashes() {
+        let link = parse(&format!(
+            "magnet:?xt=urn:btmh:1220{}&xt=urn:btih:{}",
+            INFO_HASH_V2, INFO_HASH
+        ));
+        assert_eq!(link.info_hash, info_hash());
+        assert_eq!(link.info_hash_v2, Some(info_hash_v2()));
+        assert!(link.has_v1);
+    }
+
+    #[test]
+    fn peers_web_seeds_and_file_selection() {
+        let link = parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe=10.0.0.1:6881&x.pe=%5B%3A%3A1%5D%3A51413&x.pe=peer.example.com:6882\
+             &ws=http%3A%2F%2Fseed.example.com%2Ffiles%2F&so=0,2,4-6",
+            INFO_HASH
+        ));
+        assert_eq!(
+            link.peers,
+            vec!["10.0.0.1:6881", "[::1]:51413", "peer.example.com:6882"]
+        );
+        assert_eq!(link.web_seeds, vec!["http://seed.example.com/files/"]);
+        assert_eq!(link.select_only, vec![0..=0, 2..=2, 4..=6]);
+        let selected: Vec<usize> = (0..8).filter(|index| link.is_selected(*index)).collect();
+        assert_eq!(selected, vec![0, 2, 4, 5, 6]);
+
+        // Without `so`, every file is selected
+        let link = parse(&format!("magnet:?xt=urn:btih:{}", INFO_HASH));
+        assert!(link.is_selected(0) && link.is_selected(1000));
+    }
+
+    #[test]
+    fn invalid_links_are_rejected() {
+        let invalid = [
+            format!("http://example.com/?xt=urn:btih:{}", INFO_HASH),
+            "magnet:?dn=no%20info%20hash".to_string(),
+            "magnet:?xt=urn:sha1:YNCKHTQCWBTRNJIV4WNAE52SJUQCZO5C".to_string(),
+            format!("magnet:?xt=urn:btih:{}", &INFO_HASH[..39]),
+            format!("magnet:?xt=urn:btih:{}zz", &INFO_HASH[..38]),
+            format!("magnet:?xt=urn:btih:{}", INFO_HASH_BASE32.replace('Y', "1")),
+            format!("magnet:?xt=urn:btmh:1120{}", INFO_HASH_V2),
+            format!("magnet:?xt=urn:btmh:1220{}", &INFO_HASH_V2[..62]),
+            format!("magnet:?xt=urn:btih:{}&tr=not%20a%20url", INFO_HASH),
+            format!("magnet:?xt=urn:btih:{}&x.pe=10.0.0.1", INFO_HASH),
+            format!("magnet:?xt=urn:btih:{}&x.pe=10.0.0.1:70000", INFO_HASH),
+            format!("magnet:?xt=urn:btih:{}&x.pe=:6881", INFO_HASH),
+            format!("magnet:?xt=urn:btih:{}&ws=not%20a%20url", INFO_HASH),
+            format!("magnet:?xt=urn:btih:{}&so=6-4", INFO_HASH),
+        ];
+        for link in invalid {
+            assert!(link.parse::<MagnetLink>().is_err(), "{}", link);
+        }
+    }
+
+    #[test]
+    fn links_are_written_back_as_they_were_read() {
+        let links = [
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=sample+file&tr=udp%3A%2F%2Ftracker.example.com%3A6969\
+                 &tr=http%3A%2F%2Ftracker.example.org%2Fannounce&ws=http%3A%2F%2Fseed.example.com%2F\
+                 &x.pe=10.0.0.1%3A6881&so=0%2C3-5",
+                INFO_HASH
+            ),
+            format!("magnet:?xt=urn:btmh:1220{}", INFO_HASH_V2),
+            format!(
+                "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=hybrid",
+                INFO_HASH, INFO_HASH_V2
+            ),
+        ];
+        for text in links {
+            let link = parse(&text);
+            let written = link.to_string();
+            assert_eq!(written, text);
+
+            let again = parse(&written);
+            assert_eq!(again.info_hash, link.info_hash);
+            assert_eq!(again.info_hash_v2, link.info_hash_v2);
+            assert_eq!(again.has_v1, link.has_v1);
+            assert_eq!(again.name, link.name);
+            assert_eq!(again.trackers, link.trackers);
+            assert_eq!(again.web_seeds, link.web_seeds);
+            assert_eq!(again.peers, link.peers);
+            assert_eq!(again.select_only, link.select_only);
+        }
+    }
+
+    #[test]
+    fn base32_is_decoded_in_either_case() {
+        assert_eq!(base32_decode("MZXW6YTBOI"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("mzxw6ytboi"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("MY"), Some(b"f".to_vec()));
+        assert_eq!(base32_decode(""), Some(vec![]));
+        assert_eq!(base32_decode("MY======"), None);
+        assert_eq!(base32_decode("M1"), None);
+    }
+
+    #[test]
+    fn file_selection_is_parsed() {
+        assert_eq!(parse_select_only("3").unwrap(), vec![3..=3]);
+        assert_eq!(
+            parse_select_only("0,2,4-6, 8 - 9").unwrap(),
+            vec![0..=0, 2..=2, 4..=6, 8..=9]
+        );
+        for invalid in ["", "a", "1,", "-1", "1-", "4-2", "1-2-3"] {
+            assert!(parse_select_only(invalid).is_err(), "{}", invalid);
+        }
     }
 }
@@ -0,0 +1,188 @@
+use crate::structs::request::HashRequest;
+use crate::structs::torrent::TorrentInfo;
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks the merkle trees of v2 torrents are built from: their leaves are the hashes of these blocks
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Most hashes we ask for, or send, in one `hashes` message
+pub const MAX_HASH_REQUEST_LENGTH: u32 = 512;
+
+/// The hash of a 16 KiB block: a leaf of the merkle trees of v2 torrents (BEP 52).
+///
+/// Each file has its own binary tree of SHA-256 hashes, whose leaves are the hashes of its 16 KiB blocks.
+/// The leaves beyond the end of the file, needed to make the number of leaves a power of two, are zero hashes.
+/// The root of the tree is the `pieces root` of the file, in the info dictionary. The layer of the tree
+/// whose nodes cover a piece each is the piece layer of the file, in the `piece layers` of the torrent,
+/// unless the file fits in a single piece.
+/// @link: https://www.bittorrent.org/beps/bep_0052.html
+pub fn block_hash(block: &[u8]) -> [u8; 32] {
+    Sha256::digest(block).into()
+}
+
+/// The hashes of the 16 KiB blocks of some data: the leaves of its tree.
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(block_hash).collect()
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The root of a subtree of `2^height` zero leaves, which pads the upper layers of a tree.
+pub fn pad_hash(height: u32) -> [u8; 32] {
+    (0..height).fold([0; 32], |hash, _| hash_pair(&hash, &hash))
+}
+
+/// The root of the tree over `hashes`, padded with `pad` up to `width` nodes, a power of two.
+pub fn merkle_root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    tree_layers(hashes, width, pad)
+        .last()
+        .and_then(|layer| layer.first().copied())
+        .unwrap_or(pad)
+}
+
+/// Every layer of the tree over `hashes`, padded with `pad` up to `width` nodes, from `hashes` to the root.
+fn tree_layers(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> Vec<Vec<[u8; 32]>> {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(hashes.len()).next_power_of_two(), pad);
+    let mut layers = vec![layer];
+    while let Some(layer) = layers.last().filter(|layer| layer.len() > 1) {
+        let parents = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(parents);
+    }
+    layers
+}
+
+/// Number of 16 KiB blocks in a piece, a power of two
+fn blocks_per_piece(piece_length: usize) -> usize {
+    (piece_length / MERKLE_BLOCK_SIZE).max(1)
+}
+
+/// The layer of the piece hashes, counted from the block hashes
+pub fn piece_layer_index(piece_length: usize) -> u32 {
+    blocks_per_piece(piece_length).trailing_zeros()
+}
+
+/// The hash of a piece in the piece layer of its file: the root of the subtree over its blocks,
+/// padded with zero hashes when the piece is the last one of the file.
+pub fn piece_hash(data: &[u8], piece_length: usize) -> [u8; 32] {
+    merkle_root(&block_hashes(data), blocks_per_piece(piece_length), [0; 32])
+}
+
+/// The `pieces root` of a file from its data. The tree only has as many leaves as the file needs,
+/// so this is how files which fit in a piece are checked.
+pub fn file_root(data: &[u8]) -> [u8; 32] {
+    let leaves = block_hashes(data);
+    merkle_root(&leaves, leaves.len(), [0; 32])
+}
+
+/// The `pieces root` of a file longer than a piece, from its piece layer.
+pub fn layer_root(layer: &[[u8; 32]], piece_length: usize) -> [u8; 32] {
+    let pad = pad_hash(piece_layer_index(piece_length));
+    merkle_root(layer, layer.len(), pad)
+}
+
+/// Check a node against the root of its tree, given its uncle hashes: the sibling of the node,
+/// then the sibling of its parent, and so on up to the root.
+/// `index` is the position of the node in its layer.
+pub fn verify_proof(node: [u8; 32], index: usize, uncles: &[[u8; 32]], root: &[u8; 32]) -> bool {
+    let (hash, index) = uncles.iter().fold((node, index), |(hash, index), uncle| {
+        let parent = if index % 2 == 0 {
+            hash_pair(&hash, uncle)
+        } else {
+            hash_pair(uncle, &hash)
+        };
+        (parent, index / 2)
+    });
+    index == 0 && hash == *root
+}
+
+/// Check a 16 KiB block of a file on its own, against the `pieces root` of the file.
+pub fn verify_block(
+    block: &[u8],
+    block_index: usize,
+    uncles: &[[u8; 32]],
+    root: &[u8; 32],
+) -> bool {
+    verify_proof(block_hash(block), block_index, uncles, root)
+}
+
+/// Check the answer to a hash request against the `pieces root` it was asked for,
+/// and return the hashes of the base layer which were asked for.
+///
+/// The base hashes must prove themselves up to the root: either they are the whole layer,
+/// or the uncle hashes of their subtree follow them.
+pub fn verify_hashes<'a>(request: &HashRequest, hashes: &'a [[u8; 32]]) -> Option<&'a [[u8; 32]]> {
+    let length = request.length as usize;
+    if !length.is_power_of_two()
+        || !(request.index as usize).is_multiple_of(length)
+        || hashes.len() < length
+    {
+        return None;
+    }
+    let (base, uncles) = hashes.split_at(length);
+    let subtree_root = merkle_root(base, length, [0; 32]);
+    let subtree_index = request.index as usize / length;
+    verify_proof(subtree_root, subtree_index, uncles, &request.pieces_root).then_some(base)
+}
+
+/// The hash request for the piece layer hashes of a file with `piece_count` pieces, from `index`.
+/// Each request covers [`MAX_HASH_REQUEST_LENGTH`] hashes at most, with the uncle hashes up to the root.
+pub fn piece_layer_request(
+    pieces_root: [u8; 32],
+    piece_length: usize,
+    piece_count: usize,
+    index: usize,
+) -> HashRequest {
+    let width = piece_count.next_power_of_two();
+    let length = width.min(MAX_HASH_REQUEST_LENGTH as usize);
+    HashRequest {
+        pieces_root,
+        base_layer: piece_layer_index(piece_length),
+        index: (index - index % length) as u32,
+        length: length as u32,
+        proof_layers: (width / length).trailing_zeros(),
+    }
+}
+
+/// Answer the hash request of a peer, from the piece layers of the torrent.
+/// `None` when the request is invalid, or for hashes below the piece layer, which we don't keep.
+pub fn answer_hash_request(info: &TorrentInfo, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+    let piece_length = info.piece_length as usize;
+    let layer = info.piece_layers.get(&request.pieces_root)?;
+    let length = request.length as usize;
+    let index = request.index as usize;
+    if request.base_layer != piece_layer_index(piece_length)
+        || !length.is_power_of_two()
+        || length > MAX_HASH_REQUEST_LENGTH as usize
+        || !index.is_multiple_of(length)
+        || index >= layer.len()
+    {
+        return None;
+    }
+
+    let pad = pad_hash(piece_layer_index(piece_length));
+    let layers = tree_layers(layer, layer.len(), pad);
+    let mut hashes = layers[0].get(index..index + length)?.to_vec();
+    // The uncles start at the layer of the root of the requested subtree
+    let mut node = index / length;
+    for layer in layers
+        .iter()
+        .skip(length.trailing_zeros() as usize)
+        .take(request.proof_layers as usize)
+    {
+        let Some(uncle) = layer.get(node ^ 1) else {
+            break;
+        };
+        hashes.push(*uncle);
+        node /= 2;
+    }
+    Some(hashes)
+}
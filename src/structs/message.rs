@@ -1,26 +1,78 @@
-#[derive(Debug)]
-pub struct Message {
-    /// message length prefix (4 bytes)
-    prefix: [u8; 4],
+use crate::structs::request::{HashRequest, Request};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-    /// message id (1 byte)
-    message_id: u8,
+/// Largest message we accept from a peer
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 20;
 
-    /// All non-keepalive messages start with a single byte which gives their type.
-    /// The possible values are:
-    ///
-    /// 0 - choke
-    /// 1 - unchoke
-    /// 2 - interested
-    /// 3 - not interested
-    /// 4 - have
-    /// 5 - bitfield
-    /// 6 - request
-    /// 7 - piece
-    /// 8 - cancel
-    ///
-    /// 'choke', 'unchoke', 'interested', and 'not interested' have no payload.
-    pub payload: Vec<u8>,
+/// Length of the payload of `hash request` and `hash reject` messages, which starts `hashes` messages too
+const HASH_REQUEST_LENGTH: usize = 48;
+
+/// A message of the peer wire protocol.
+///
+/// All non-keepalive messages start with a single byte which gives their type.
+/// The possible values are:
+///
+/// 0 - choke
+/// 1 - unchoke
+/// 2 - interested
+/// 3 - not interested
+/// 4 - have
+/// 5 - bitfield
+/// 6 - request
+/// 7 - piece
+/// 8 - cancel
+/// 9 - port (BEP 5)
+/// 13 to 17 - Fast extension messages (BEP 6)
+/// 20 - extension protocol messages (BEP 10)
+/// 21 to 23 - merkle tree hashes of v2 torrents (BEP 52)
+///
+/// 'choke', 'unchoke', 'interested', and 'not interested' have no payload.
+/// @link: https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A zero-length message, sent to keep the connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: i32,
+    },
+    /// The raw bitfield of the pieces the peer has
+    Bitfield(Vec<u8>),
+    Request(Request),
+    Piece {
+        index: i32,
+        begin: i32,
+        block: Vec<u8>,
+    },
+    Cancel(Request),
+    /// The port the peer's DHT node listens on
+    Port(u16),
+    SuggestPiece {
+        index: i32,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest(Request),
+    AllowedFast {
+        index: i32,
+    },
+    /// An extension protocol message: the extension message id, and its payload
+    Extension {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// Ask for hashes of the merkle tree of a file
+    HashRequest(HashRequest),
+    /// The hashes asked for, followed by the uncle hashes proving them
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashRequest),
 }
 
 #[repr(u8)]
@@ -35,79 +87,486 @@ pub enum MessageType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
     Extension = 20,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
+}
+
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("unknown message id {0}")]
+    UnknownId(u8),
+
+    #[error("invalid payload length {length} for {message_type:?} message")]
+    InvalidLength {
+        message_type: MessageType,
+        length: usize,
+    },
+
+    #[error("message of {0} bytes is too long")]
+    TooLong(usize),
+
+    #[error("message is truncated")]
+    Truncated,
+
+    #[error("{0} is out of range")]
+    OutOfRange(u32),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
-impl MessageType {
-    pub fn from_byte(message_id: u8) -> MessageType {
+impl TryFrom<u8> for MessageType {
+    type Error = MessageError;
+
+    fn try_from(message_id: u8) -> Result<Self, Self::Error> {
         match message_id {
-            0 => MessageType::Choke,
-            1 => MessageType::Unchoke,
-            2 => MessageType::Interested,
-            3 => MessageType::NotInterested,
-            4 => MessageType::Have,
-            5 => MessageType::Bitfield,
-            6 => MessageType::Request,
-            7 => MessageType::Piece,
-            8 => MessageType::Cancel,
-            20 => MessageType::Extension,
-            _ => panic!("Unknown message type: {}", message_id),
+            0 => Ok(MessageType::Choke),
+            1 => Ok(MessageType::Unchoke),
+            2 => Ok(MessageType::Interested),
+            3 => Ok(MessageType::NotInterested),
+            4 => Ok(MessageType::Have),
+            5 => Ok(MessageType::Bitfield),
+            6 => Ok(MessageType::Request),
+            7 => Ok(MessageType::Piece),
+            8 => Ok(MessageType::Cancel),
+            9 => Ok(MessageType::Port),
+            13 => Ok(MessageType::SuggestPiece),
+            14 => Ok(MessageType::HaveAll),
+            15 => Ok(MessageType::HaveNone),
+            16 => Ok(MessageType::RejectRequest),
+            17 => Ok(MessageType::AllowedFast),
+            20 => Ok(MessageType::Extension),
+            21 => Ok(MessageType::HashRequest),
+            22 => Ok(MessageType::Hashes),
+            23 => Ok(MessageType::HashReject),
+            _ => Err(MessageError::UnknownId(message_id)),
         }
     }
 }
 
 impl Message {
-    pub fn new(message_id: u8, payload: Vec<u8>) -> Message {
-        let prefix_length: i32 = (1 + payload.len()) as i32; // 1 byte for message_id + payload size
-        Message {
-            prefix: prefix_length.to_be_bytes(), // Convert to big-endian byte array
-            message_id,
-            payload,
+    /// The type of the message, `None` for keep-alive messages.
+    pub fn message_type(&self) -> Option<MessageType> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => MessageType::Choke,
+            Message::Unchoke => MessageType::Unchoke,
+            Message::Interested => MessageType::Interested,
+            Message::NotInterested => MessageType::NotInterested,
+            Message::Have { .. } => MessageType::Have,
+            Message::Bitfield(_) => MessageType::Bitfield,
+            Message::Request(_) => MessageType::Request,
+            Message::Piece { .. } => MessageType::Piece,
+            Message::Cancel(_) => MessageType::Cancel,
+            Message::Port(_) => MessageType::Port,
+            Message::SuggestPiece { .. } => MessageType::SuggestPiece,
+            Message::HaveAll => MessageType::HaveAll,
+            Message::HaveNone => MessageType::HaveNone,
+            Message::RejectRequest(_) => MessageType::RejectRequest,
+            Message::AllowedFast { .. } => MessageType::AllowedFast,
+            Message::Extension { .. } => MessageType::Extension,
+            Message::HashRequest(_) => MessageType::HashRequest,
+            Message::Hashes { .. } => MessageType::Hashes,
+            Message::HashReject(_) => MessageType::HashReject,
+        })
+    }
+
+    /// Decode a message from its id and payload.
+    pub fn decode(message_id: u8, payload: &[u8]) -> Result<Message, MessageError> {
+        let message_type = MessageType::try_from(message_id)?;
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(MessageError::InvalidLength {
+                    message_type,
+                    length: payload.len(),
+                })
+            }
+        };
+
+        let message = match message_type {
+            MessageType::Choke => expect_length(0).map(|_| Message::Choke)?,
+            MessageType::Unchoke => expect_length(0).map(|_| Message::Unchoke)?,
+            MessageType::Interested => expect_length(0).map(|_| Message::Interested)?,
+            MessageType::NotInterested => expect_length(0).map(|_| Message::NotInterested)?,
+            MessageType::HaveAll => expect_length(0).map(|_| Message::HaveAll)?,
+            MessageType::HaveNone => expect_length(0).map(|_| Message::HaveNone)?,
+            MessageType::Have => {
+                expect_length(4)?;
+                Message::Have {
+                    index: read_i32(payload, 0)?,
+                }
+            }
+            MessageType::SuggestPiece => {
+                expect_length(4)?;
+                Message::SuggestPiece {
+                    index: read_i32(payload, 0)?,
+                }
+            }
+            MessageType::AllowedFast => {
+                expect_length(4)?;
+                Message::AllowedFast {
+                    index: read_i32(payload, 0)?,
+                }
+            }
+            MessageType::Bitfield => Message::Bitfield(payload.to_vec()),
+            MessageType::Request => {
+                expect_length(12)?;
+                Message::Request(read_request(payload)?)
+            }
+            MessageType::Cancel => {
+                expect_length(12)?;
+                Message::Cancel(read_request(payload)?)
+            }
+            MessageType::RejectRequest => {
+                expect_length(12)?;
+                Message::RejectRequest(read_request(payload)?)
+            }
+            MessageType::Piece => {
+                if payload.len() < 8 {
+                    return Err(MessageError::InvalidLength {
+                        message_type,
+                        length: payload.len(),
+                    });
+                }
+                Message::Piece {
+                    index: read_i32(payload, 0)?,
+                    begin: read_i32(payload, 4)?,
+                    block: payload[8..].to_vec(),
+                }
+            }
+            MessageType::Port => {
+                expect_length(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            MessageType::Extension => {
+                let (id, payload) = payload.split_first().ok_or(MessageError::InvalidLength {
+                    message_type,
+                    length: 0,
+                })?;
+                Message::Extension {
+                    id: *id,
+                    payload: payload.to_vec(),
+                }
+            }
+            MessageType::HashRequest => {
+                expect_length(HASH_REQUEST_LENGTH)?;
+                Message::HashRequest(read_hash_request(payload)?)
+            }
+            MessageType::HashReject => {
+                expect_length(HASH_REQUEST_LENGTH)?;
+                Message::HashReject(read_hash_request(payload)?)
+            }
+            MessageType::Hashes => {
+                if payload.len() < HASH_REQUEST_LENGTH
+                    || !(payload.len() - HASH_REQUEST_LENGTH).is_multiple_of(32)
+                {
+                    return Err(MessageError::InvalidLength {
+                        message_type,
+                        length: payload.len(),
+                    });
+                }
+                Message::Hashes {
+                    request: read_hash_request(payload)?,
+                    hashes: payload[HASH_REQUEST_LENGTH..]
+                        .chunks_exact(32)
+                        .map(|hash| hash.try_into().expect("Chunk of 32 bytes"))
+                        .collect(),
+                }
+            }
+        };
+        Ok(message)
+    }
+
+    /// Decode a whole message, length prefix included.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Message, MessageError> {
+        if bytes.len() < 4 {
+            return Err(MessageError::Truncated);
+        }
+        let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(MessageError::TooLong(length));
+        }
+        if bytes.len() - 4 != length {
+            return Err(MessageError::Truncated);
+        }
+        match bytes[4..].split_first() {
+            None => Ok(Message::KeepAlive),
+            Some((message_id, payload)) => Message::decode(*message_id, payload),
+        }
+    }
+
+    /// Read a message from the wire.
+    ///
+    /// A message with an unknown id is consumed before [`MessageError::UnknownId`] is returned,
+    /// so that the caller can skip it and keep reading.
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message, MessageError> {
+        let mut prefix = [0u8; 4];
+        reader.read_exact(&mut prefix).await?;
+        let length = u32::from_be_bytes(prefix) as usize;
+        if length == 0 {
+            return Ok(Message::KeepAlive);
+        }
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(MessageError::TooLong(length));
         }
+
+        let mut buf = vec![0u8; length];
+        reader.read_exact(&mut buf).await?;
+        Message::decode(buf[0], &buf[1..])
     }
 
-    pub fn set_length(&mut self, length: u32) {
-        self.prefix = length.to_be_bytes();
+    /// Convert the message to bytes for transmission, length prefix included.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MessageError> {
+        let Some(message_type) = self.message_type() else {
+            return Ok(vec![0, 0, 0, 0]);
+        };
+
+        let mut payload = Vec::new();
+        match self {
+            Message::Have { index }
+            | Message::SuggestPiece { index }
+            | Message::AllowedFast { index } => payload.extend_from_slice(&index.to_be_bytes()),
+            Message::Bitfield(bitfield) => payload.extend_from_slice(bitfield),
+            Message::Request(request)
+            | Message::Cancel(request)
+            | Message::RejectRequest(request) => payload.extend(request.to_bytes()),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+            }
+            Message::Port(port) => payload.extend_from_slice(&port.to_be_bytes()),
+            Message::Extension { id, payload: data } => {
+                payload.push(*id);
+                payload.extend_from_slice(data);
+            }
+            Message::HashRequest(request) | Message::HashReject(request) => {
+                payload.extend(request.to_bytes())
+            }
+            Message::Hashes { request, hashes } => {
+                payload.extend(request.to_bytes());
+                hashes
+                    .iter()
+                    .for_each(|hash| payload.extend_from_slice(hash));
+            }
+            _ => {}
+        }
+
+        // 1 byte for message_id + payload size
+        let length = 1 + payload.len();
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(MessageError::TooLong(length));
+        }
+        let mut bytes = Vec::with_capacity(4 + length);
+        bytes.extend_from_slice(&(length as u32).to_be_bytes());
+        bytes.push(message_type as u8);
+        bytes.extend(payload);
+        Ok(bytes)
     }
+}
+
+fn read_i32(payload: &[u8], offset: usize) -> Result<i32, MessageError> {
+    let bytes = payload
+        .get(offset..offset + 4)
+        .ok_or(MessageError::Truncated)?;
+    let value = u32::from_be_bytes(bytes.try_into().expect("Slice of 4 bytes"));
+    i32::try_from(value).map_err(|_| MessageError::OutOfRange(value))
+}
+
+fn read_request(payload: &[u8]) -> Result<Request, MessageError> {
+    Ok(Request::new(
+        read_i32(payload, 0)?,
+        read_i32(payload, 4)?,
+        read_i32(payload, 8)?,
+    ))
+}
+
+fn read_u32(payload: &[u8], offset: usize) -> Result<u32, MessageError> {
+    let bytes = payload
+        .get(offset..offset + 4)
+        .ok_or(MessageError::Truncated)?;
+    Ok(u32::from_be_bytes(
+        bytes.try_into().expect("Slice of 4 bytes"),
+    ))
+}
 
-    pub fn from_bytes(bytes: &[u8]) -> Message {
-        if bytes.len() < 5 {
-            panic!("Message is too short");
+fn read_hash_request(payload: &[u8]) -> Result<HashRequest, MessageError> {
+    let pieces_root = payload.get(..32).ok_or(MessageError::Truncated)?;
+    Ok(HashRequest {
+        pieces_root: pieces_root.try_into().expect("Slice of 32 bytes"),
+        base_layer: read_u32(payload, 32)?,
+        index: read_u32(payload, 36)?,
+        length: read_u32(payload, 40)?,
+        proof_layers: read_u32(payload, 44)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_request() -> HashRequest {
+        HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 0,
+            index: 4,
+            length: 2,
+            proof_layers: 1,
         }
-        let prefix = [bytes[0], bytes[1], bytes[2], bytes[3]];
-        let message_id = bytes[4];
-        let payload = bytes[5..].to_vec();
-
-        Message {
-            prefix,
-            message_id,
-            payload,
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 3 },
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            Message::Request(Request::new(1, 16384, 16384)),
+            Message::Piece {
+                index: 1,
+                begin: 16384,
+                block: vec![1, 2, 3],
+            },
+            Message::Cancel(Request::new(1, 0, 16384)),
+            Message::Port(6881),
+            Message::SuggestPiece { index: 9 },
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest(Request::new(2, 0, 100)),
+            Message::AllowedFast { index: 5 },
+            Message::Extension {
+                id: 3,
+                payload: b"d1:ai1ee".to_vec(),
+            },
+            Message::HashRequest(hash_request()),
+            Message::Hashes {
+                request: hash_request(),
+                hashes: vec![[1; 32], [2; 32], [3; 32]],
+            },
+            Message::HashReject(hash_request()),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
         }
     }
 
-    /// Convert the Message struct to bytes for transmission
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend(&self.prefix);
-        bytes.push(self.message_id.clone());
-        bytes.extend_from_slice(&self.payload);
+    #[test]
+    fn short_input_is_rejected() {
+        assert!(matches!(
+            Message::from_bytes(&[0, 0, 1]),
+            Err(MessageError::Truncated)
+        ));
+        // The length prefix announces more than there is
+        assert!(matches!(
+            Message::from_bytes(&[0, 0, 0, 5, 4, 0, 0]),
+            Err(MessageError::Truncated)
+        ));
 
-        bytes
+        for (message_type, payload) in [
+            (MessageType::Choke, vec![0]),
+            (MessageType::Have, vec![0, 0, 1]),
+            (MessageType::Request, vec![0; 11]),
+            (MessageType::Piece, vec![0; 7]),
+            (MessageType::Port, vec![0x1a]),
+            (MessageType::Extension, vec![]),
+            (MessageType::HashRequest, vec![0; HASH_REQUEST_LENGTH - 1]),
+            // A partial hash after the request
+            (MessageType::Hashes, vec![0; HASH_REQUEST_LENGTH + 31]),
+        ] {
+            let result = Message::decode(message_type as u8, &payload);
+            assert!(
+                matches!(
+                    result,
+                    Err(MessageError::InvalidLength { message_type: found, length })
+                        if found == message_type && length == payload.len()
+                ),
+                "{:?}: {:?}",
+                message_type,
+                result
+            );
+        }
     }
 
-    pub fn message_type(&self) -> MessageType {
-        match self.message_id {
-            0 => MessageType::Choke,
-            1 => MessageType::Unchoke,
-            2 => MessageType::Interested,
-            3 => MessageType::NotInterested,
-            4 => MessageType::Have,
-            5 => MessageType::Bitfield,
-            6 => MessageType::Request,
-            7 => MessageType::Piece,
-            8 => MessageType::Cancel,
-            20 => MessageType::Extension,
-            _ => panic!("Unknown message type: {}", self.message_id),
+    #[test]
+    fn unknown_ids_and_out_of_range_values() {
+        for message_id in [10, 12, 18, 24, 255] {
+            assert!(matches!(
+                Message::decode(message_id, &[]),
+                Err(MessageError::UnknownId(id)) if id == message_id
+            ));
         }
+        // Piece indexes are signed on our side
+        assert!(matches!(
+            Message::decode(MessageType::Have as u8, &[0x80, 0, 0, 0]),
+            Err(MessageError::OutOfRange(0x8000_0000))
+        ));
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let mut bytes = ((MAX_MESSAGE_LENGTH + 1) as u32).to_be_bytes().to_vec();
+        bytes.push(MessageType::Piece as u8);
+        assert!(matches!(
+            Message::from_bytes(&bytes),
+            Err(MessageError::TooLong(length)) if length == MAX_MESSAGE_LENGTH + 1
+        ));
+
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; MAX_MESSAGE_LENGTH],
+        };
+        assert!(matches!(piece.to_bytes(), Err(MessageError::TooLong(_))));
+    }
+
+    #[tokio::test]
+    async fn read_from_skips_unknown_messages() {
+        let mut wire = vec![0, 0, 0, 3, 200, 1, 2];
+        wire.extend(Message::KeepAlive.to_bytes().unwrap());
+        wire.extend(Message::Have { index: 7 }.to_bytes().unwrap());
+        wire.extend(((MAX_MESSAGE_LENGTH + 1) as u32).to_be_bytes());
+        let mut reader = wire.as_slice();
+
+        // The unknown message is consumed, so the next one can be read
+        assert!(matches!(
+            Message::read_from(&mut reader).await,
+            Err(MessageError::UnknownId(200))
+        ));
+        assert_eq!(
+            Message::read_from(&mut reader).await.unwrap(),
+            Message::KeepAlive
+        );
+        assert_eq!(
+            Message::read_from(&mut reader).await.unwrap(),
+            Message::Have { index: 7 }
+        );
+        // Too long to be buffered: the length prefix is enough to refuse it
+        assert!(matches!(
+            Message::read_from(&mut reader).await,
+            Err(MessageError::TooLong(_))
+        ));
+        assert!(matches!(
+            Message::read_from(&mut reader).await,
+            Err(MessageError::Io(_))
+        ));
     }
 }
@@ -0,0 +1,314 @@
+use crate::structs::extension::{
+    Extension, ExtensionHandler, ExtensionMessageType, MetadataInfo, UT_METADATA,
+};
+use crate::structs::merkle;
+use crate::structs::peers::Peer;
+use crate::structs::torrent::TorrentInfo;
+use crate::utils::decoder;
+use anyhow::{anyhow, Error};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tokio::task::JoinSet;
+
+/// Size of a metadata piece: every piece but the last one is exactly this long
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Metadata larger than this is refused, whatever the peers say
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Get the info dictionary of a torrent from its peers, knowing only its info hash (magnet links).
+///
+/// The metadata is split in pieces of [`METADATA_PIECE_SIZE`], requested from every peer in parallel:
+/// each peer asks for the next missing piece once it got the previous one.
+/// A peer which rejects a request, or fails, is not asked again.
+/// Once every piece is there, the metadata is checked against the info hash, which is the truncated
+/// SHA-256 hash of the metadata for v2-only torrents (BEP 52). When it doesn't match,
+/// it is fetched again: from one peer at a time if several peers sent it, to find out which one is wrong.
+/// @link: https://www.bittorrent.org/beps/bep_0009.html
+pub async fn fetch_metadata(
+    peers: &[(Peer, Extension)],
+    info_hash: &[u8; 20],
+) -> Result<TorrentInfo, Error> {
+    let mut sources: Vec<MetadataSource> = peers
+        .iter()
+        .filter_map(|(peer, extension)| {
+            let id = extension.inner.id(UT_METADATA)?;
+            let size = usize::try_from(extension.metadata_size?).ok()?;
+            (size > 0 && size <= MAX_METADATA_SIZE).then(|| MetadataSource {
+                peer: peer.clone(),
+                id,
+                size,
+            })
+        })
+        .collect();
+    if sources.is_empty() {
+        return Err(anyhow!("No peer can send the torrent metadata"));
+    }
+
+    let mut last_error = anyhow!("No peer could send the torrent metadata");
+    let mut one_at_a_time = false;
+    while let Some(first) = sources.first() {
+        // Peers which disagree on the size can't be sending the same metadata
+        let size = first.size;
+        let peers = if one_at_a_time {
+            vec![sources.remove(0)]
+        } else {
+            let (peers, others) = sources.into_iter().partition(|source| source.size == size);
+            sources = others;
+            peers
+        };
+
+        let (metadata, senders, others) = match download_metadata(peers, size).await {
+            Ok(downloaded) => downloaded,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                last_error = e;
+                continue;
+            }
+        };
+        if Sha1::digest(&metadata).as_slice() == info_hash
+            || Sha256::digest(&metadata)[..20] == info_hash[..]
+        {
+            return TorrentInfo::from_bytes(&metadata);
+        }
+
+        let addresses: Vec<SocketAddr> = senders.iter().map(|source| source.peer.address).collect();
+        eprintln!("Metadata from {:?} doesn't match the info hash", addresses);
+        last_error = anyhow!("The metadata doesn't match the info hash");
+        if senders.len() > 1 {
+            one_at_a_time = true;
+            sources.extend(senders);
+        }
+        sources.extend(others);
+    }
+    Err(last_error)
+}
+
+/// Get the piece layers of a v2-only torrent from its peers, for the files longer than a piece:
+/// the torrent file has them, but the metadata of a magnet link doesn't.
+///
+/// The layer of each file is requested in [`merkle::MAX_HASH_REQUEST_LENGTH`] hashes at most,
+/// each request going to the peers in turn until one of them sends hashes matching the root of the file.
+/// @link: https://www.bittorrent.org/beps/bep_0052.html#hash-request
+pub async fn fetch_piece_layers(peers: &mut [Peer], info: &mut TorrentInfo) -> Result<(), Error> {
+    let piece_length = info.piece_length as usize;
+    for file in info.missing_piece_layers() {
+        let Some(root) = file.pieces_root else {
+            continue;
+        };
+        let piece_count = (file.length as usize).div_ceil(piece_length);
+        let mut layer = Vec::with_capacity(piece_count);
+        while layer.len() < piece_count {
+            let request = merkle::piece_layer_request(root, piece_length, piece_count, layer.len());
+            let mut hashes = None;
+            for peer in peers.iter_mut() {
+                match peer.request_hashes(request).await {
+                    Ok(received) => {
+                        hashes = Some(received);
+                        break;
+                    }
+                    Err(e) => eprintln!("{:?}", e),
+                }
+            }
+            let hashes = hashes.ok_or(anyhow!(
+                "No peer could send the piece layer of {}",
+                file.path.join("/")
+            ))?;
+            // The last hashes of the layer are padding
+            layer.extend(hashes.into_iter().take(piece_count - layer.len()));
+        }
+        info.add_piece_layer(root, layer)?;
+    }
+    Ok(())
+}
+
+/// A peer which can send the metadata
+#[derive(Debug)]
+struct MetadataSource {
+    peer: Peer,
+
+    /// The ID of `ut_metadata` messages for the peer
+    id: u8,
+
+    /// The size of the metadata according to the peer
+    size: usize,
+}
+
+/// Download the `size` bytes of metadata from `peers`, all of them agreeing on its size.
+/// Returns the metadata, the peers which sent pieces of it, and the ones which sent none.
+async fn download_metadata(
+    peers: Vec<MetadataSource>,
+    size: usize,
+) -> Result<(Vec<u8>, Vec<MetadataSource>, Vec<MetadataSource>), Error> {
+    let pieces = Arc::new(MetadataPieces::new(size));
+    let spawn = |join_set: &mut JoinSet<_>, source: MetadataSource| {
+        let pieces = pieces.clone();
+        join_set.spawn(async move {
+            let result = request_pieces(source.peer.clone(), source.id, &pieces).await;
+            (source, result)
+        });
+    };
+
+    let mut last_error = anyhow!("No peer could send the torrent metadata");
+    let mut idle = vec![];
+    let mut join_set = JoinSet::new();
+    for source in peers {
+        spawn(&mut join_set, source);
+    }
+    while let Some(joined) = join_set.join_next().await {
+        let (source, result) = joined?;
+        match result {
+            Ok(()) => idle.push(source),
+            Err(e) => {
+                eprintln!(
+                    "Skipping peer {} for metadata: {:?}",
+                    source.peer.address, e
+                );
+                last_error = e;
+            }
+        }
+        // The pieces a failed peer was asked for go to the peers done with theirs
+        if !pieces.lock().missing.is_empty() {
+            for source in idle.drain(..) {
+                spawn(&mut join_set, source);
+            }
+        }
+    }
+
+    let mut pieces = pieces.lock();
+    if !pieces.missing.is_empty() {
+        return Err(last_error.context(format!(
+            "{} of {} metadata pieces are missing",
+            pieces.missing.len(),
+            pieces.data.len()
+        )));
+    }
+    let metadata = pieces.data.drain(..).flatten().flatten().collect();
+    let (senders, others) = idle
+        .into_iter()
+        .partition(|source| pieces.senders.contains(&source.peer.address));
+    Ok((metadata, senders, others))
+}
+
+/// Ask a peer for the missing pieces of the metadata, one at a time, until none is left.
+async fn request_pieces(mut peer: Peer, id: u8, pieces: &MetadataPieces) -> Result<(), Error> {
+    loop {
+        let Some(piece) = pieces.lock().next_missing() else {
+            return Ok(());
+        };
+        let result = match peer.request_metadata(id, piece as u32).await {
+            Ok(data) => pieces.lock().received(piece, data, peer.address),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            pieces.lock().missing.push(piece);
+            return Err(e);
+        }
+    }
+}
+
+/// The pieces of the metadata, as they are downloaded.
+#[derive(Debug)]
+struct MetadataPieces {
+    inner: Mutex<MetadataState>,
+}
+
+#[derive(Debug)]
+struct MetadataState {
+    size: usize,
+
+    /// The received pieces, in order
+    data: Vec<Option<Vec<u8>>>,
+
+    /// The pieces nobody is requesting
+    missing: Vec<usize>,
+
+    /// The peers which sent pieces
+    senders: HashSet<SocketAddr>,
+}
+
+impl MetadataPieces {
+    fn new(size: usize) -> MetadataPieces {
+        let count = size.div_ceil(METADATA_PIECE_SIZE);
+        MetadataPieces {
+            inner: Mutex::new(MetadataState {
+                size,
+                data: vec![None; count],
+                // Popped from the end: the first pieces are requested first
+                missing: (0..count).rev().collect(),
+                senders: HashSet::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MetadataState> {
+        self.inner.lock().expect("Locking metadata pieces")
+    }
+}
+
+impl MetadataState {
+    fn next_missing(&mut self) -> Option<usize> {
+        self.missing.pop()
+    }
+
+    /// Keep a piece sent by `sender`, if it has the expected length.
+    fn received(&mut self, piece: usize, data: Vec<u8>, sender: SocketAddr) -> Result<(), Error> {
+        let expected = METADATA_PIECE_SIZE.min(self.size - piece * METADATA_PIECE_SIZE);
+        if data.len() != expected {
+            return Err(anyhow!(
+                "Metadata piece {} is {} bytes long, expected {}",
+                piece,
+                data.len(),
+                expected
+            ));
+        }
+        self.data[piece] = Some(data);
+        self.senders.insert(sender);
+        Ok(())
+    }
+}
+
+/// The `ut_metadata` extension of a download connection: the metadata is only sent by seeders,
+/// so the requests of the peer are rejected.
+#[derive(Debug, Default)]
+pub struct MetadataExtension {
+    /// The pieces to reject the requests of
+    rejected: VecDeque<u32>,
+}
+
+impl ExtensionHandler for MetadataExtension {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<(), Error> {
+        // The answers to our requests are read by `Peer::request_metadata`.
+        // Their piece of metadata comes right after the bencoded dictionary
+        let end = decoder::bencoded_value_end(payload, 0)?;
+        let message: MetadataInfo = serde_bencode::from_bytes(&payload[..end])?;
+        if message.msg_type == ExtensionMessageType::Request as u8 {
+            self.rejected.push_back(message.piece);
+        }
+        Ok(())
+    }
+
+    fn poll_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(piece) = self.rejected.pop_front() else {
+            return Ok(None);
+        };
+        let reject = MetadataInfo {
+            msg_type: ExtensionMessageType::Reject as u8,
+            piece,
+            total_size: None,
+        };
+        Ok(Some(serde_bencode::to_bytes(&reject)?))
+    }
+
+    fn next_poll(&self) -> Option<Instant> {
+        (!self.rejected.is_empty()).then(Instant::now)
+    }
+}
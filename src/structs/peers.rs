@@ -1,46 +1,116 @@
+use crate::structs::bitfield::Bitfield;
+use crate::structs::dht::Dht;
+use crate::structs::download::BLOCK_SIZE;
 use crate::structs::extension::{
-    Extension, ExtensionMessageType, InnerDictionnary, MetadataInfo, MetadataPayload,
+    Extension, ExtensionMessageType, ExtensionRegistry, MetadataInfo, MetadataPayload,
+    UT_METADATA_ID,
 };
 use crate::structs::handshake::Handshake;
 use crate::structs::magnet::MagnetLink;
-use crate::structs::message::{Message, MessageType};
-use crate::structs::request::Request;
-use crate::structs::torrent::{Torrent, TorrentInfo};
-use crate::utils::trackers;
-use anyhow::Context;
+use crate::structs::merkle;
+use crate::structs::message::{Message, MessageError, MAX_MESSAGE_LENGTH};
+use crate::structs::request::HashRequest;
+use crate::structs::seeder::PORT;
+use crate::structs::torrent::Torrent;
+use crate::utils::trackers::TrackerList;
+use crate::utils::{decoder, trackers};
 use anyhow::Error;
+use anyhow::{anyhow, Context};
 use rand::random;
-use serde::de::Visitor;
+use serde::de::{SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
-use std::cmp::min;
+use serde_bytes::ByteBuf;
 use std::fmt;
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::{Mutex, OwnedSemaphorePermit};
-use tokio::task::JoinSet;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::timeout;
 
 /// Generate a peer id on 20 characters
 /// Ex: 47001398037243657525
 pub fn generate_peer_id() -> [u8; 20] {
     let mut peer_id: [u8; 20] = [0u8; 20];
-    for i in 0..20 {
-        peer_id[i] = (random::<u8>() % 10) + 48; // 48 is the ASCII code for '0'
+    for byte in peer_id.iter_mut() {
+        *byte = (random::<u8>() % 10) + 48; // 48 is the ASCII code for '0'
     }
     peer_id
 }
 
+/// What we announce as left to download when we don't have the metadata yet: a single block
+const UNKNOWN_LEFT: u64 = BLOCK_SIZE as u64;
+
+/// Length of a compact IPv4 peer: the IP address (4 bytes) then the port (2 bytes)
+pub const COMPACT_V4_LEN: usize = 6;
+
+/// Length of a compact IPv6 peer: the IP address (16 bytes) then the port (2 bytes)
+pub const COMPACT_V6_LEN: usize = 18;
+
+/// Read a compact peer, in network byte order.
+/// The IP version is given by the length of `bytes`.
+pub fn compact_address(bytes: &[u8]) -> Option<SocketAddr> {
+    let ip = match bytes.len() {
+        COMPACT_V4_LEN => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[..4]).ok()?)),
+        COMPACT_V6_LEN => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[..16]).ok()?)),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([bytes[bytes.len() - 2], bytes[bytes.len() - 1]]);
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Write a peer in its compact form, the reverse of [`compact_address`].
+pub fn to_compact(address: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+/// Read a list of compact peers of `address_len` bytes each.
+pub fn compact_addresses(bytes: &[u8], address_len: usize) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(address_len)
+        .filter_map(compact_address)
+        .collect()
+}
+
 #[derive(Debug)]
-pub struct PeerList(pub Vec<SocketAddrV4>);
+pub struct PeerList(pub Vec<SocketAddr>);
 
-struct PeersVisitor;
+struct PeersVisitor {
+    /// Length of each compact peer: [`COMPACT_V4_LEN`] in `peers`, [`COMPACT_V6_LEN`] in `peers6`
+    address_len: usize,
+}
 
 impl<'de> Deserialize<'de> for PeerList {
     fn deserialize<D>(deserializer: D) -> Result<PeerList, D::Error>
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        // Compact peer lists are strings, the others are lists of dictionaries
+        deserializer.deserialize_any(PeersVisitor {
+            address_len: COMPACT_V4_LEN,
+        })
+    }
+}
+
+impl PeerList {
+    /// Deserialize the `peers6` key of a tracker response: the compact form of IPv6 peers.
+    /// @link: https://www.bittorrent.org/beps/bep_0007.html
+    pub fn deserialize_v6<'de, D>(deserializer: D) -> Result<Option<PeerList>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_bytes(PeersVisitor {
+                address_len: COMPACT_V6_LEN,
+            })
+            .map(Some)
     }
 }
 
@@ -48,112 +118,310 @@ impl<'de> Visitor<'de> for PeersVisitor {
     type Value = PeerList;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
+        write!(formatter, "{} bytes per peer, the IP address followed by the 2 bytes of the port number, or a list of peer dictionaries", self.address_len)
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        if v.len() % 6 != 0 {
+        if !v.len().is_multiple_of(self.address_len) {
             return Err(E::invalid_length(v.len(), &self));
         }
+        Ok(PeerList(compact_addresses(v, self.address_len)))
+    }
 
-        let peers: Vec<SocketAddrV4> = v
-            .chunks_exact(6)
-            .map(|chunk_6| {
-                SocketAddrV4::new(
-                    Ipv4Addr::new(chunk_6[0], chunk_6[1], chunk_6[2], chunk_6[3]),
-                    u16::from_be_bytes([chunk_6[4], chunk_6[5]]),
-                )
-            })
-            .collect();
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut peers = vec![];
+        while let Some(peer) = seq.next_element::<DictPeer>()? {
+            // The ip may also be a domain name
+            match peer.ip.parse::<IpAddr>() {
+                Ok(ip) => peers.push(SocketAddr::new(ip, peer.port)),
+                Err(_) => eprintln!("Skipping peer {}:{}", peer.ip, peer.port),
+            }
+        }
         Ok(PeerList(peers))
     }
 }
 
-impl PeerList {
-    /// Get the list of peers from a magnet link
-    pub async fn get_peers_from(magnet_link: &MagnetLink) -> Result<Vec<SocketAddrV4>, Error> {
-        let encoded_info = magnet_link
-            .info_hash
-            .iter()
-            .map(|b| format!("%{:02x}", b))
-            .collect::<String>();
+/// A peer of a non-compact peer list
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct DictPeer {
+    #[serde(rename = "peer id", default)]
+    peer_id: Option<ByteBuf>,
+    ip: String,
+    port: u16,
+}
 
+impl PeerList {
+    /// Get the list of peers from a magnet link: its own peers (`x.pe`), its trackers and the DHT
+    pub async fn get_peers_from(
+        magnet_link: &MagnetLink,
+        dht: Option<&Arc<Dht>>,
+    ) -> Result<Vec<SocketAddr>, Error> {
         let query_params = trackers::QueryParams {
             peer_id: generate_peer_id().iter().map(|b| *b as char).collect(),
-            port: 6881,
+            port: PORT,
             uploaded: 0,
             downloaded: 0,
-            // This is an arbitrary value, we don't know the total length of the file yet.
-            left: 999,
+            // We don't know the total length of the file before we get the metadata,
+            // but we must not be taken for a seeder
+            left: UNKNOWN_LEFT,
             compact: 1,
+            trackerid: None,
+            event: None,
         };
 
-        let tracker_response =
-            trackers::get_tracker_info(&magnet_link.tracker_url, query_params, encoded_info)
+        let announce_trackers = async {
+            if magnet_link.trackers.is_empty() {
+                return None;
+            }
+            let tracker_response = TrackerList::new(magnet_link.tiers())
+                .announce(&query_params, &magnet_link.info_hash)
                 .await
-                .context("Getting tracker info")?;
-        // println!("Tracker Response: {:?}", tracker_response);
-        let peers = tracker_response.peers.unwrap_or(PeerList(vec![]));
-        Ok(peers.0)
+                .context("Getting tracker info");
+            // println!("Tracker Response: {:?}", tracker_response);
+            Some(tracker_response.map(|response| response.addresses()))
+        };
+        let announce_dht = async {
+            let dht = dht?;
+            Some(
+                dht.get_peers(magnet_link.info_hash)
+                    .await
+                    .context("Getting peers from the DHT"),
+            )
+        };
+        let resolve_peers = async {
+            if magnet_link.peers.is_empty() {
+                return None;
+            }
+            let mut addresses = vec![];
+            for peer in &magnet_link.peers {
+                match lookup_host(peer.as_str()).await {
+                    Ok(resolved) => addresses.extend(resolved),
+                    Err(e) => eprintln!("Skipping peer {}: {:?}", peer, e),
+                }
+            }
+            Some(Ok(addresses))
+        };
+        let (tracker_peers, dht_peers, link_peers) =
+            tokio::join!(announce_trackers, announce_dht, resolve_peers);
+
+        let mut peers = vec![];
+        let mut answered = false;
+        let mut last_error =
+            anyhow!("The magnet link has no tracker nor peer, and the DHT is disabled");
+        for result in [tracker_peers, dht_peers, link_peers].into_iter().flatten() {
+            match result {
+                Ok(addresses) => {
+                    answered = true;
+                    peers.extend(addresses);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        if !answered {
+            return Err(last_error);
+        }
+        peers.sort();
+        peers.dedup();
+        Ok(peers)
     }
 
     /// Get the list of peers from a torrent file
-    pub async fn get_peers(torrent: &Torrent) -> Result<Vec<SocketAddrV4>, Error> {
-        let encoded_info = torrent.info_hash_string();
-
+    pub async fn get_peers(torrent: &Torrent) -> Result<Vec<SocketAddr>, Error> {
         let query_params = trackers::QueryParams {
             peer_id: generate_peer_id().iter().map(|b| *b as char).collect(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.info.length as u64,
+            port: PORT,
+            uploaded: torrent.stats.uploaded.load(Ordering::Relaxed),
+            downloaded: torrent.stats.downloaded.load(Ordering::Relaxed),
+            left: torrent.stats.left.load(Ordering::Relaxed),
             compact: 1,
+            trackerid: None,
+            event: None,
         };
 
-        let tracker_response =
-            trackers::get_tracker_info(&torrent.announce, query_params, encoded_info)
-                .await
-                .context("Getting tracker info")?;
+        let tracker_response = torrent
+            .trackers
+            .announce(&query_params, &torrent.info.get_hash())
+            .await
+            .context("Getting tracker info")?;
         println!("Tracker Response: {:?}", tracker_response);
-        let peers = tracker_response.peers.unwrap_or(PeerList(vec![]));
-        for address in &peers.0 {
+        let peers = tracker_response.addresses();
+        for address in &peers {
             println!("{}", address);
         }
-        Ok(peers.0)
+        Ok(peers)
     }
 }
+/// A connection to a peer.
+///
+/// The socket is owned by a connection task: messages sent through the peer are
+/// queued for that task to write, and the messages it reads are queued for [`Peer::read`].
+/// The connection is closed once every clone of the peer is dropped.
 #[derive(Debug, Clone)]
 pub struct Peer {
-    pub address: SocketAddrV4,
-    pub stream: Arc<Mutex<TcpStream>>,
+    pub address: SocketAddr,
     pub peer_id: String,
     pub extensions: Vec<u8>,
+
+    /// Messages waiting to be written by the connection task
+    outgoing: mpsc::Sender<Message>,
+
+    /// Messages read by the connection task
+    incoming: Arc<Mutex<mpsc::Receiver<Message>>>,
+
+    /// What the connection task learns from the messages going through
+    shared: Arc<std::sync::Mutex<SharedState>>,
+}
+
+#[derive(Debug)]
+struct SharedState {
+    state: PeerState,
+    pieces: PeerPieces,
+
+    /// The latest extension handshake of the peer
+    extensions: Option<Extension>,
+
+    /// When the peer last sent us anything, keep-alives included
+    last_received: Instant,
+}
+
+impl SharedState {
+    fn new() -> SharedState {
+        SharedState {
+            state: PeerState::default(),
+            pieces: PeerPieces::default(),
+            extensions: None,
+            last_received: Instant::now(),
+        }
+    }
+}
+
+/// The pieces a peer announced, from its `bitfield`, `have`, `have all` and `have none` messages.
+///
+/// The connection doesn't know how many pieces the torrent has,
+/// so the bitfield covers every piece announced so far, up to the most a `bitfield` message can hold.
+#[derive(Debug, Default)]
+struct PeerPieces {
+    bitfield: Bitfield,
+    has_all: bool,
+}
+
+impl PeerPieces {
+    fn on_received(&mut self, message: &Message) {
+        match message {
+            Message::Bitfield(bytes) => {
+                self.bitfield = Bitfield::from_bytes(bytes, bytes.len() * 8);
+            }
+            // Larger indexes can't be pieces of the torrent, and would make us allocate their bitfield
+            Message::Have { index }
+                if *index >= 0 && (*index as usize) < MAX_MESSAGE_LENGTH * 8 =>
+            {
+                let index = *index as usize;
+                if index >= self.bitfield.len() {
+                    self.bitfield = Bitfield::from_bytes(self.bitfield.as_bytes(), index + 1);
+                }
+                self.bitfield.set(index);
+            }
+            Message::HaveAll => self.has_all = true,
+            Message::HaveNone => *self = PeerPieces::default(),
+            _ => {}
+        }
+    }
+}
+
+/// The choke and interest state of a connection, on both sides.
+///
+/// Connections start out choked and not interested.
+/// @link: https://www.bittorrent.org/beps/bep_0003.html#peer-protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    /// We refuse to serve the requests of the peer
+    pub am_choking: bool,
+    /// We want pieces from the peer
+    pub am_interested: bool,
+    /// The peer refuses to serve our requests
+    pub peer_choking: bool,
+    /// The peer wants pieces from us
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        PeerState {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl PeerState {
+    /// Update the flags for a message we sent.
+    pub fn on_sent(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.am_choking = true,
+            Message::Unchoke => self.am_choking = false,
+            Message::Interested => self.am_interested = true,
+            Message::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+
+    /// Update the flags for a message we received.
+    pub fn on_received(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
 }
 
-pub const MESSAGE_TYPES_WITHOUT_PAYLOAD: [MessageType; 4] = [
-    MessageType::Choke,
-    MessageType::Unchoke,
-    MessageType::Interested,
-    MessageType::NotInterested,
-];
-const BLOCK_SIZE: i32 = 16 * 1024; // = 16384 bytes
+/// How long we wait for a peer to accept the TCP connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long we wait for the handshake of a peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a peer may stay silent, keep-alives included, before we give up on it.
+/// Being choked is no reason to drop a peer, as long as it keeps the connection alive.
+/// @link: https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// Peers close connections which stay silent for 2 minutes
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Number of messages buffered in each direction of a connection
+const CHANNEL_SIZE: usize = 64;
 
 impl Peer {
-    pub async fn new(address: SocketAddrV4, info_hash: &[u8; 20]) -> Result<Peer, Error> {
-        let mut tcp_stream = TcpStream::connect(address)?;
+    pub async fn new(address: SocketAddr, info_hash: &[u8; 20]) -> Result<Peer, Error> {
+        let mut tcp_stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .context("Connecting to peer (timeout)")??;
         let peer_id = generate_peer_id();
 
         let req_handshake = Handshake::new(*info_hash, peer_id);
-        tcp_stream.write(&req_handshake.to_bytes())?;
+        tcp_stream.write_all(&req_handshake.to_bytes()).await?;
 
-        #[allow(unused_mut)]
-        let mut buffer_response = &mut [0; 68];
-        tcp_stream.read(buffer_response)?;
+        let mut buffer_response = [0; 68];
+        timeout(
+            HANDSHAKE_TIMEOUT,
+            tcp_stream.read_exact(&mut buffer_response),
+        )
+        .await
+        .context("Reading handshake (timeout)")??;
 
-        let handshake_response = Handshake::from_bytes(buffer_response);
+        let handshake_response = Handshake::from_bytes(&buffer_response);
         if handshake_response.info_hash != *info_hash {
             return Err(Error::msg("Hashes don't match !"));
         }
@@ -164,111 +432,142 @@ impl Peer {
         }
 
         println!("Peer ID: {}", handshake_response.peer_id_string());
+        let (outgoing, outgoing_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (incoming_sender, incoming) = mpsc::channel(CHANNEL_SIZE);
+        let shared = Arc::new(std::sync::Mutex::new(SharedState::new()));
+        tokio::spawn(Peer::run_connection(
+            tcp_stream,
+            outgoing_receiver,
+            incoming_sender,
+            shared.clone(),
+        ));
+
         Ok(Peer {
             address,
-            stream: Arc::new(Mutex::new(tcp_stream)),
             peer_id: handshake_response.peer_id_string(),
             extensions,
+            outgoing,
+            incoming: Arc::new(Mutex::new(incoming)),
+            shared,
         })
     }
 
-    pub async fn get_pieces(&mut self) -> Result<Vec<u8>, Error> {
-        let message = &self.read().await?;
-        // println!("Response received: {:?}", message.message_type());
-        if !matches!(message.message_type(), MessageType::Bitfield) {
-            return Err(Error::msg("Expected bitfield message"));
-        }
+    fn lock_shared(&self) -> std::sync::MutexGuard<'_, SharedState> {
+        self.shared.lock().expect("Locking peer state")
+    }
 
-        let peer_pieces = message.payload.clone();
-        // println!("Bitfield pieces: {:?}", peer_pieces);
-        Ok(peer_pieces)
+    /// The current choke and interest flags of the connection.
+    pub fn state(&self) -> PeerState {
+        self.lock_shared().state
     }
 
-    pub async fn send_interest(&mut self) -> Result<(), Error> {
-        // Send interested message
-        let interested_message = Message::new(MessageType::Interested as u8, vec![]);
-        self.send(interested_message).await?;
+    /// Whether the peer supports the extension protocol (BEP 10), from the reserved bytes of its handshake.
+    pub fn supports_extensions(&self) -> bool {
+        self.extensions.first().is_some_and(|byte| byte & 0x10 != 0)
+    }
 
-        // Read the response
-        let message = self.read().await?;
-        println!("Response received: {:?}", message.message_type()); // Should receive an Unchoke message.
+    /// The extension handshake of the peer, once it sent it.
+    pub fn remote_extensions(&self) -> Option<Extension> {
+        self.lock_shared().extensions.clone()
+    }
 
-        if !matches!(message.message_type(), MessageType::Unchoke) {
-            return Err(Error::msg("Expected unchoke message"));
+    /// The pieces the peer announced so far, out of the `piece_count` pieces of the torrent.
+    pub fn pieces(&self, piece_count: usize) -> Bitfield {
+        let shared = self.lock_shared();
+        if shared.pieces.has_all {
+            let mut all = Bitfield::new(piece_count);
+            (0..piece_count).for_each(|index| all.set(index));
+            return all;
         }
-
-        Ok(())
+        Bitfield::from_bytes(shared.pieces.bitfield.as_bytes(), piece_count)
     }
 
-    pub async fn download_piece(
-        &mut self,
-        piece_index: i32,
-        piece_len: i32,
-    ) -> Result<Vec<u8>, Error> {
-        println!(
-            "  - Downloading piece: piece_index: {}, piece_len: {}",
-            piece_index, piece_len
-        );
-        let mut set = JoinSet::new();
-        let mut piece_data = vec![0u8; piece_len as usize]; // Pre-allocate the vector for the piece data
-
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(5));
-
-        let spawn = |join_set: &mut JoinSet<_>,
-                     mut peer: Peer,
-                     piece_index: i32,
-                     block_offset: i32,
-                     block_length: i32,
-                     permit: OwnedSemaphorePermit| {
-            join_set.spawn(async move {
-                match peer
-                    .download_block(piece_index, block_offset, block_length)
-                    .await
+    /// The connection task: reads and writes messages until either side of the socket closes,
+    /// or every handle to the peer is dropped.
+    async fn run_connection(
+        tcp_stream: TcpStream,
+        mut outgoing: mpsc::Receiver<Message>,
+        incoming: mpsc::Sender<Message>,
+        shared: Arc<std::sync::Mutex<SharedState>>,
+    ) {
+        let (mut reader, mut writer) = tcp_stream.into_split();
+
+        let read_loop = async {
+            loop {
+                let message = Message::read_from(&mut reader).await;
+                if matches!(message, Ok(_) | Err(MessageError::UnknownId(_))) {
+                    shared.lock().expect("Locking peer state").last_received = Instant::now();
+                }
+                let message = match message {
+                    // Keep-alives only count as a sign of life
+                    Ok(Message::KeepAlive) => continue,
+                    Ok(message) => message,
+                    // Messages we don't know about are ignored
+                    Err(MessageError::UnknownId(_)) => continue,
+                    Err(e) => return Err(Error::from(e)),
+                };
+                // The state is updated before the message is handed over, so that it is
+                // already up to date when the message is read
                 {
-                    Ok(data) => {
-                        drop(permit);
-                        (block_offset, data)
+                    let mut shared = shared.lock().expect("Locking peer state");
+                    shared.state.on_received(&message);
+                    shared.pieces.on_received(&message);
+                    if let Message::Extension { id: 0, payload } = &message {
+                        if let Ok(handshake) = serde_bencode::from_bytes::<Extension>(payload) {
+                            shared.extensions = Some(handshake);
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("Error downloading block: {:?}", e);
-                        (block_offset, vec![])
+                }
+                if incoming.send(message).await.is_err() {
+                    return Ok::<(), Error>(());
+                }
+            }
+        };
+
+        let write_loop = async {
+            loop {
+                match timeout(KEEP_ALIVE_INTERVAL, outgoing.recv()).await {
+                    Ok(Some(message)) => {
+                        writer.write_all(&message.to_bytes()?).await?;
+                        shared
+                            .lock()
+                            .expect("Locking peer state")
+                            .state
+                            .on_sent(&message);
                     }
+                    Ok(None) => return Ok::<(), Error>(()),
+                    Err(_) => writer.write_all(&Message::KeepAlive.to_bytes()?).await?,
                 }
-            });
+            }
         };
-        for offset in (0..piece_len).step_by(BLOCK_SIZE as usize) {
-            let length = min(BLOCK_SIZE, piece_len - offset);
-            let permit = semaphore.clone().acquire_owned().await?;
-            spawn(&mut set, self.clone(), piece_index, offset, length, permit);
-        }
 
-        while let Some(join_result) = set.join_next().await {
-            let (block_offset, block_data) = join_result.context("Joining block")?;
-            let block_offset = block_offset as usize;
-            let block_data_len = block_data.len();
-            piece_data[block_offset..block_offset + block_data_len].copy_from_slice(&block_data);
+        let result = tokio::select! {
+            result = read_loop => result,
+            result = write_loop => result,
+        };
+        if let Err(e) = result {
+            eprintln!("Connection closed: {:?}", e);
         }
-
-        Ok(piece_data)
     }
 
-    pub async fn download_block(
-        &mut self,
-        piece_index: i32,
-        begin: i32,
-        length: i32,
-    ) -> Result<Vec<u8>, Error> {
-        // println!(
-        //     "    - Downloading block: piece_index: {}, begin: {}, length: {}",
-        //     piece_index, begin, length
-        // );
-        let request = Request::new(piece_index, begin, length);
-        let message = Message::new(MessageType::Request as u8, request.to_bytes());
-        self.send(message).await?;
-        let response = self.read().await?;
-        let block_data = &response.payload[8..];
+    /// Wait for the peer to announce the pieces it has, which are then available from [`Peer::pieces`].
+    pub async fn get_pieces(&mut self) -> Result<(), Error> {
+        let message = self.read().await?;
+        match message {
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => Ok(()),
+            _ => Err(anyhow!(
+                "Expected bitfield message, got {:?}",
+                message.message_type()
+            )),
+        }
+    }
 
-        Ok(block_data.to_vec())
+    /// Tell the peer we are interested. Its unchoke message is handled by the download.
+    pub async fn send_interest(&mut self) -> Result<(), Error> {
+        if !self.state().am_interested {
+            self.send(Message::Interested).await?;
+        }
+        Ok(())
     }
 
     /// Extension messages follow the standard BitTorrent message format:
@@ -288,132 +587,159 @@ impl Peer {
     /// For example, the inner dictionary contents might be {"ut_metadata": 1, "ut_pex": 2},
     /// indicating that your peer supports the "utmetadata" and "utpex" extensions with IDs 1 and 2 respectively.
     pub async fn send_ext_handshake(&mut self) -> Result<Extension, Error> {
-        // Extension support message
-        let extension = Extension {
-            inner: InnerDictionnary { ut_metadata: 1 },
-            metadata_size: 0,
-        };
-
-        // Message ID is 0 for the extension handshake
-        let mut bytes = vec![0];
-        bytes.extend(serde_bencode::to_bytes(&extension)?);
-        let message = Message::new(20, bytes);
+        // Whether the torrent is private is only known once we have its metadata:
+        // only `ut_metadata` is advertised until then
+        let message = ExtensionRegistry::new().handshake_message(&self.address)?;
         self.send(message).await?;
 
-        // Read peer extension message
-        let response = self
-            .read()
-            .await
-            .context("Reading extension message response")?;
-        let (message_id, bencoded_dict) = response.payload.split_at(1);
-        assert_eq!(message_id[0], 0);
-        let ext: Extension = serde_bencode::from_bytes(bencoded_dict)?;
-        Ok(ext)
-    }
-
-    pub async fn get_extension_info(
-        &mut self,
-        extension: &Extension,
-        magnet_link: &MagnetLink,
-    ) -> Result<TorrentInfo, Error> {
-        println!(
-            "Peer Metadata Extension ID: {}",
-            extension.inner.ut_metadata
-        );
-        println!("Peer Metadata Size: {}", extension.metadata_size);
-        let (_meta, torrent_info) = self
-            .request_metadata(extension.inner.ut_metadata, 0)
-            .await?;
-
-        println!("Length: {}", torrent_info.length);
-        println!("Info Hash: {}", hex::encode(&magnet_link.info_hash));
-
-        println!("Piece Length: {}", torrent_info.piece_length);
-        for chunk in torrent_info.pieces.chunks(20) {
-            println!("{:}", hex::encode(chunk));
+        // Read peer extension message, other messages may come first
+        loop {
+            let response = self
+                .read()
+                .await
+                .context("Reading extension message response")?;
+            if let Message::Extension {
+                id: 0,
+                payload: bencoded_dict,
+            } = response
+            {
+                return Ok(serde_bencode::from_bytes(&bencoded_dict)?);
+            }
         }
-        // Verify hash is valid
-        assert_eq!(torrent_info.get_hash(), magnet_link.info_hash);
-
-        Ok(torrent_info)
     }
 
+    /// Request a piece of the metadata (the info dictionary) of the torrent.
+    ///
+    /// The peer answers with a `data` message holding the piece right after its bencoded dictionary,
+    /// or with a `reject` message when it doesn't have the metadata or doesn't want to send it.
+    /// @link: https://www.bittorrent.org/beps/bep_0009.html
     pub async fn request_metadata(
         &mut self,
         extensions_id: u8,
-        piece_index: u8,
-    ) -> Result<(MetadataInfo, TorrentInfo), Error> {
+        piece_index: u32,
+    ) -> Result<Vec<u8>, Error> {
         let payload = MetadataPayload {
             piece: piece_index,
             msg_type: ExtensionMessageType::Request as u8,
         };
 
-        // Message ID is =the extension ID
-        let mut bytes = vec![extensions_id];
-        bytes.extend(serde_bencode::to_bytes(&payload)?);
-        let message = Message::new(20, bytes);
+        // Message ID is the extension ID of the peer
+        let message = Message::Extension {
+            id: extensions_id,
+            payload: serde_bencode::to_bytes(&payload)?,
+        };
         self.send(message).await?;
 
-        // Read peer extension message
-        let response = self
-            .read()
-            .await
-            .context("Reading metadata message response")?;
-
-        let (message_id, remains) = response.payload.split_at(1);
-
-        println!("MessageID {:?}", message_id);
-        println!(
-            "Received payload {:?}",
-            remains.iter().map(|v| *v as char).collect::<String>()
-        );
-        println!("Received message ID {}", message_id[0]);
-        // TODO: fix this
-        // assert_eq!(message_id[0], extensions_id);
-        let metadata_info: MetadataInfo =
-            serde_bencode::from_bytes(remains).context("Decoding metadata info")?;
-        assert_eq!(metadata_info.msg_type, ExtensionMessageType::Data as u8);
-        let meta_size = serde_bencode::to_bytes(&metadata_info)
-            .context("Encoding metadata info")?
-            .len();
-
-        println!("Received Metadata {:?}", metadata_info);
-        let torrent_info: TorrentInfo =
-            serde_bencode::from_bytes(&remains[meta_size..]).context("Decoding torrent info")?;
-        println!("Received TorrentInfo {:?}", torrent_info);
-
-        Ok((metadata_info, torrent_info))
+        loop {
+            // The peer answers with the extension ID we advertised, other messages may come first
+            let response = self
+                .read()
+                .await
+                .context("Reading metadata message response")?;
+            let Message::Extension {
+                id: UT_METADATA_ID,
+                payload: remains,
+            } = response
+            else {
+                continue;
+            };
+
+            // The metadata piece is appended right after the bencoded dictionary
+            let meta_size =
+                decoder::bencoded_value_end(&remains, 0).context("Decoding metadata info")?;
+            let metadata_info: MetadataInfo = serde_bencode::from_bytes(&remains[..meta_size])
+                .context("Decoding metadata info")?;
+            match metadata_info.msg_type {
+                // We don't have the metadata to share yet: the peer's own requests are rejected
+                msg_type if msg_type == ExtensionMessageType::Request as u8 => {
+                    let reject = MetadataPayload {
+                        piece: metadata_info.piece,
+                        msg_type: ExtensionMessageType::Reject as u8,
+                    };
+                    self.send(Message::Extension {
+                        id: extensions_id,
+                        payload: serde_bencode::to_bytes(&reject)?,
+                    })
+                    .await?;
+                }
+                msg_type
+                    if msg_type == ExtensionMessageType::Data as u8
+                        && metadata_info.piece == piece_index =>
+                {
+                    return Ok(remains[meta_size..].to_vec());
+                }
+                msg_type
+                    if msg_type == ExtensionMessageType::Reject as u8
+                        && metadata_info.piece == piece_index =>
+                {
+                    return Err(anyhow!(
+                        "Peer {} rejected the request for metadata piece {}",
+                        self.address,
+                        piece_index
+                    ));
+                }
+                // Answers about other pieces, and message types we don't know, are skipped
+                _ => {}
+            }
+        }
+    }
+
+    /// Request hashes of the merkle tree of a file of a v2 torrent, and check them against its root.
+    ///
+    /// The peer answers with a `hashes` message holding the hashes and their proof,
+    /// or with a `hash reject` message when it doesn't have them.
+    /// @link: https://www.bittorrent.org/beps/bep_0052.html#hash-request
+    pub async fn request_hashes(&mut self, request: HashRequest) -> Result<Vec<[u8; 32]>, Error> {
+        self.send(Message::HashRequest(request)).await?;
+
+        loop {
+            // Other messages may come first
+            match self.read().await.context("Reading hashes response")? {
+                Message::Hashes {
+                    request: answered,
+                    hashes,
+                } if answered == request => {
+                    return merkle::verify_hashes(&request, &hashes)
+                        .map(<[[u8; 32]]>::to_vec)
+                        .ok_or(anyhow!(
+                            "Peer {} sent hashes which don't match their pieces root",
+                            self.address
+                        ));
+                }
+                Message::HashReject(rejected) if rejected == request => {
+                    return Err(anyhow!(
+                        "Peer {} rejected the request for hashes {:?}",
+                        self.address,
+                        request
+                    ));
+                }
+                _ => continue,
+            }
+        }
     }
 
     pub async fn send(&mut self, message: Message) -> Result<(), Error> {
-        let mut tcp_stream = self.stream.lock().await;
-        tcp_stream.write(&message.to_bytes())?;
-        Ok(())
+        self.outgoing
+            .send(message)
+            .await
+            .map_err(|_| anyhow!("Connection to {} is closed", self.address))
     }
+
     pub async fn read(&mut self) -> Result<Message, Error> {
-        let mut tcp_stream = self.stream.lock().await;
-        #[allow(unused_mut)]
-        let mut buf = &mut [0; 4];
-        tcp_stream
-            .read_exact(buf)
-            .context("Reading message length (Are Piece index/len correct ?")?;
-        let prefix = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
-
-        let mut buf = vec![0; 1];
-        tcp_stream
-            .read_exact(&mut buf)
-            .context("Reading message id")?;
-        let message_id = buf[0];
-
-        let message_type = MessageType::from_byte(message_id);
-        if MESSAGE_TYPES_WITHOUT_PAYLOAD.contains(&message_type) {
-            return Ok(Message::new(message_id, vec![]));
+        let mut incoming = self.incoming.lock().await;
+        loop {
+            // Keep-alives don't reach us, but they push the deadline back
+            let silence = self.lock_shared().last_received.elapsed();
+            let Some(remaining) = SILENCE_TIMEOUT.checked_sub(silence) else {
+                return Err(anyhow!(
+                    "Peer {} stayed silent for {:?}",
+                    self.address,
+                    silence
+                ));
+            };
+            if let Ok(message) = timeout(remaining, incoming.recv()).await {
+                return message.ok_or(anyhow!("Connection to {} is closed", self.address));
+            }
         }
-
-        let mut buf = vec![0; prefix - 1]; // -1 for message_id
-        tcp_stream
-            .read_exact(&mut buf)
-            .context("Reading message payload")?;
-        Ok(Message::new(message_id, buf))
     }
 }
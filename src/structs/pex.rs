@@ -0,0 +1,223 @@
+use crate::structs::extension::{Extension, ExtensionHandler, UT_PEX};
+use crate::structs::peers::{
+    compact_address, compact_addresses, to_compact, COMPACT_V4_LEN, COMPACT_V6_LEN,
+};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A peer may get one `ut_pex` message per minute
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers added, and most peers dropped, in a single message
+const MAX_PEX_PEERS: usize = 50;
+
+/// The peer prefers encrypted connections
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+/// The peer is a seed, or only uploads
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer supports uTP
+pub const FLAG_UTP: u8 = 0x04;
+/// The peer supports `ut_holepunch`
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+/// The peer accepts incoming connections: we connected to it
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// A `ut_pex` message: the peers the sender connected to, and the ones it disconnected from,
+/// since its previous message. Peers are in their compact form, with one byte of flags per added peer.
+/// @link: https://www.bittorrent.org/beps/bep_0011.html
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub added: ByteBuf,
+
+    #[serde(rename = "added.f", default, skip_serializing_if = "is_empty")]
+    pub added_flags: ByteBuf,
+
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub added6: ByteBuf,
+
+    #[serde(rename = "added6.f", default, skip_serializing_if = "is_empty")]
+    pub added6_flags: ByteBuf,
+
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub dropped: ByteBuf,
+
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub dropped6: ByteBuf,
+}
+
+fn is_empty(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
+}
+
+impl PexMessage {
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> PexMessage {
+        let mut message = PexMessage::default();
+        for (address, flags) in added {
+            let (peers, peer_flags) = match address {
+                SocketAddr::V4(_) => (&mut message.added, &mut message.added_flags),
+                SocketAddr::V6(_) => (&mut message.added6, &mut message.added6_flags),
+            };
+            peers.extend_from_slice(&to_compact(address));
+            peer_flags.push(*flags);
+        }
+        for address in dropped {
+            let peers = match address {
+                SocketAddr::V4(_) => &mut message.dropped,
+                SocketAddr::V6(_) => &mut message.dropped6,
+            };
+            peers.extend_from_slice(&to_compact(address));
+        }
+        message
+    }
+
+    /// The added peers, with their flags (0 when the sender didn't give them)
+    pub fn added(&self) -> Vec<(SocketAddr, u8)> {
+        let added = self
+            .added
+            .chunks_exact(COMPACT_V4_LEN)
+            .zip(flags(&self.added_flags));
+        let added6 = self
+            .added6
+            .chunks_exact(COMPACT_V6_LEN)
+            .zip(flags(&self.added6_flags));
+        added
+            .chain(added6)
+            .filter_map(|(peer, flags)| Some((compact_address(peer)?, flags)))
+            .collect()
+    }
+
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        let mut dropped = compact_addresses(&self.dropped, COMPACT_V4_LEN);
+        dropped.extend(compact_addresses(&self.dropped6, COMPACT_V6_LEN));
+        dropped
+    }
+}
+
+/// The flags of each peer, then 0 for the peers without flags
+fn flags(flags: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    flags.iter().copied().chain(std::iter::repeat(0))
+}
+
+/// The peers a torrent is connected to, which its connections exchange with their peers.
+#[derive(Debug)]
+pub struct Swarm {
+    /// The connected peers, with their flags
+    connected: Mutex<HashMap<SocketAddr, u8>>,
+
+    /// Where the peers we learn about go, to be connected to
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+}
+
+impl Swarm {
+    pub fn new(discovered: mpsc::UnboundedSender<SocketAddr>) -> Swarm {
+        Swarm {
+            connected: Mutex::new(HashMap::new()),
+            discovered,
+        }
+    }
+
+    fn lock_connected(&self) -> MutexGuard<'_, HashMap<SocketAddr, u8>> {
+        self.connected.lock().expect("Locking swarm")
+    }
+}
+
+/// Peer exchange with a single peer, for as long as we are connected to it.
+///
+/// The peer is part of the swarm until the exchange is dropped.
+#[derive(Debug)]
+pub struct PeerExchange {
+    swarm: Arc<Swarm>,
+    address: SocketAddr,
+
+    /// The peers the peer knows about from us
+    sent: HashSet<SocketAddr>,
+
+    /// When we may send the next message
+    next_send: Instant,
+}
+
+impl PeerExchange {
+    pub fn new(swarm: Arc<Swarm>, address: SocketAddr, flags: u8) -> PeerExchange {
+        swarm.lock_connected().insert(address, flags);
+        PeerExchange {
+            swarm,
+            address,
+            sent: HashSet::new(),
+            next_send: Instant::now(),
+        }
+    }
+}
+
+impl ExtensionHandler for PeerExchange {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_handshake(&mut self, _handshake: &Extension, _supported: bool) {
+        if self.sent.is_empty() {
+            // The peer just told us its extensions: the first message doesn't need to wait
+            self.next_send = Instant::now();
+        }
+    }
+
+    /// The peers of a `ut_pex` message join the torrent's peer pool.
+    /// The dropped peers are left alone, since we may still be connected to them.
+    fn on_message(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let message: PexMessage = serde_bencode::from_bytes(payload)?;
+        for (address, _) in message.added() {
+            let _ = self.swarm.discovered.send(address);
+        }
+        Ok(())
+    }
+
+    /// The message to send to the peer, when it's time to send one, and the swarm changed.
+    fn poll_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if Instant::now() < self.next_send {
+            return Ok(None);
+        }
+        self.next_send = Instant::now() + PEX_INTERVAL;
+
+        let connected = self.swarm.lock_connected().clone();
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(address, _)| **address != self.address && !self.sent.contains(address))
+            .take(MAX_PEX_PEERS)
+            .map(|(address, flags)| (*address, *flags))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|address| !connected.contains_key(address))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(None);
+        }
+
+        self.sent.extend(added.iter().map(|(address, _)| *address));
+        for address in &dropped {
+            self.sent.remove(address);
+        }
+        Ok(Some(serde_bencode::to_bytes(&PexMessage::new(
+            &added, &dropped,
+        ))?))
+    }
+
+    fn next_poll(&self) -> Option<Instant> {
+        Some(self.next_send)
+    }
+}
+
+impl Drop for PeerExchange {
+    fn drop(&mut self) {
+        self.swarm.lock_connected().remove(&self.address);
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Request {
     pub piece_index: i32,
     pub begin: i32,
@@ -21,3 +22,35 @@ impl Request {
         bytes
     }
 }
+
+/// A request for hashes of the merkle tree of a file, along with the hashes proving them (BEP 52).
+/// @link: https://www.bittorrent.org/beps/bep_0052.html#hash-request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    /// The root of the tree of the file, as in its `pieces root`
+    pub pieces_root: [u8; 32],
+
+    /// The layer the hashes are taken from, counted from the 16 KiB blocks (0)
+    pub base_layer: u32,
+
+    /// Index of the first hash in the base layer, a multiple of `length`
+    pub index: u32,
+
+    /// Number of hashes, a power of two
+    pub length: u32,
+
+    /// Number of layers above the requested hashes to send the uncle hashes of
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(48);
+        bytes.extend_from_slice(&self.pieces_root);
+        bytes.extend_from_slice(&self.base_layer.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.proof_layers.to_be_bytes());
+        bytes
+    }
+}
@@ -0,0 +1,455 @@
+use crate::structs::announcer::Announcer;
+use crate::structs::bitfield::Bitfield;
+use crate::structs::handshake::Handshake;
+use crate::structs::merkle;
+use crate::structs::message::{Message, MessageError};
+use crate::structs::peers::{generate_peer_id, PeerState};
+use crate::structs::request::Request;
+use crate::structs::storage::Storage;
+use crate::structs::torrent::{Torrent, TorrentStats};
+use anyhow::{anyhow, Context, Error};
+use rand::seq::IndexedRandom;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::{self, JoinSet};
+use tokio::time::timeout;
+
+/// The port we listen on for incoming peers, and advertise to trackers
+pub const PORT: u16 = 6881;
+
+/// Largest block a peer may request from us
+const MAX_REQUEST_LENGTH: i32 = 128 * 1024;
+
+/// Number of requests a peer may queue before we start ignoring them
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// Number of peers we upload to at the same time, the optimistic unchoke included
+const UPLOAD_SLOTS: usize = 4;
+
+/// How often the peers we upload to are picked again
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves to another peer
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Serves the pieces of the torrents we have to other peers.
+#[derive(Debug)]
+pub struct Seeder {
+    peer_id: [u8; 20],
+    torrents: Mutex<HashMap<[u8; 20], Arc<SeededTorrent>>>,
+
+    /// The connected peers, for the choker
+    connections: Mutex<HashMap<u64, Arc<Connection>>>,
+    next_connection_id: AtomicU64,
+
+    /// Wakes the choker up before its next round
+    rechoke: Notify,
+}
+
+#[derive(Debug)]
+struct SeededTorrent {
+    storage: Arc<Storage>,
+    have: Bitfield,
+    stats: Arc<TorrentStats>,
+    announcer: Option<Arc<Announcer>>,
+}
+
+/// A connected peer, as seen by the choker.
+#[derive(Debug)]
+struct Connection {
+    state: Mutex<PeerState>,
+
+    /// Bytes uploaded to the peer since it connected
+    uploaded: AtomicU64,
+
+    /// Whether the choker wants the peer choked
+    choke: watch::Sender<bool>,
+}
+
+impl Connection {
+    fn lock_state(&self) -> MutexGuard<'_, PeerState> {
+        self.state.lock().expect("Locking peer state")
+    }
+
+    fn state(&self) -> PeerState {
+        *self.lock_state()
+    }
+}
+
+/// What the choker remembers from one round to the next.
+#[derive(Debug, Default)]
+struct Choker {
+    /// Bytes uploaded to each peer at the last round
+    last_uploaded: HashMap<u64, u64>,
+    optimistic: Option<u64>,
+    last_optimistic: Option<Instant>,
+}
+
+impl Default for Seeder {
+    fn default() -> Self {
+        Seeder::new()
+    }
+}
+
+impl Seeder {
+    pub fn new() -> Seeder {
+        Seeder {
+            peer_id: generate_peer_id(),
+            torrents: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(0),
+            rechoke: Notify::new(),
+        }
+    }
+
+    /// Start serving a torrent downloaded to `output`.
+    ///
+    /// The pieces on disk are hashed again, as the data may have changed since the resume file was written.
+    pub async fn add(&self, torrent: &Torrent, output: &str) -> Result<(), Error> {
+        let (info, output) = (torrent.info.clone(), output.to_string());
+        let (storage, have) = task::spawn_blocking(move || {
+            let storage = Storage::open(&info, &output)?;
+            let have = storage.verify()?;
+            Ok::<_, Error>((storage, have))
+        })
+        .await??;
+        println!(
+            "Seeding {} ({}/{} pieces)",
+            torrent.info.name,
+            have.count(),
+            have.len()
+        );
+
+        let left: usize = have
+            .missing()
+            .map(|piece_index| storage.piece_len(piece_index))
+            .sum();
+        torrent.stats.left.store(left as u64, Ordering::Relaxed);
+
+        // Keep announcing the torrent, so that peers can find us
+        let announcer = torrent
+            .announcer
+            .clone()
+            .or_else(|| torrent.start_announcer());
+
+        let seeded = SeededTorrent {
+            storage: Arc::new(storage),
+            have,
+            stats: torrent.stats.clone(),
+            announcer,
+        };
+        self.torrents
+            .lock()
+            .expect("Locking seeded torrents")
+            .insert(torrent.info.get_hash(), Arc::new(seeded));
+        Ok(())
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) {
+        let removed = self
+            .torrents
+            .lock()
+            .expect("Locking seeded torrents")
+            .remove(info_hash);
+        if let Some(announcer) = removed.and_then(|torrent| torrent.announcer.clone()) {
+            tokio::spawn(async move { announcer.stop().await });
+        }
+    }
+
+    /// Tell the trackers of every torrent that we are leaving.
+    pub async fn shutdown(&self) {
+        let announcers: Vec<Arc<Announcer>> = self
+            .torrents
+            .lock()
+            .expect("Locking seeded torrents")
+            .values()
+            .filter_map(|torrent| torrent.announcer.clone())
+            .collect();
+        let mut stops = JoinSet::new();
+        for announcer in announcers {
+            stops.spawn(async move { announcer.stop().await });
+        }
+        while stops.join_next().await.is_some() {}
+    }
+
+    /// Accept incoming peers on `port`, until the listener fails.
+    ///
+    /// The choker runs alongside, picking which of the connected peers we upload to.
+    pub async fn listen(self: Arc<Self>, port: u16) -> Result<(), Error> {
+        // IPv6 sockets also accept IPv4 peers, unless IPv6 is disabled on this host
+        let listener = match TcpListener::bind(("::", port)).await {
+            Ok(listener) => listener,
+            Err(_) => TcpListener::bind(("0.0.0.0", port))
+                .await
+                .with_context(|| format!("Listening on port {}", port))?,
+        };
+        println!("Listening for peers on port {}", port);
+
+        let accept_loop = async {
+            loop {
+                let (stream, address) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => return Err(Error::from(e)),
+                };
+                let seeder = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = seeder.serve(stream, address).await {
+                        eprintln!("Connection with {} closed: {:?}", address, e);
+                    }
+                });
+            }
+        };
+
+        tokio::select! {
+            result = accept_loop => result,
+            _ = self.clone().run_choker() => Ok(()),
+        }
+    }
+
+    /// Handle a connection from a peer, from the handshake to the last block served.
+    async fn serve(&self, stream: TcpStream, address: SocketAddr) -> Result<(), Error> {
+        let (mut reader, mut writer) = stream.into_split();
+
+        let mut buffer = [0u8; 68];
+        reader
+            .read_exact(&mut buffer)
+            .await
+            .context("Reading handshake")?;
+        let handshake = Handshake::from_bytes(&buffer);
+        if handshake.protocol_byte != 19 || &handshake.protocol != b"BitTorrent protocol" {
+            return Err(anyhow!("Invalid handshake"));
+        }
+
+        let torrent = self
+            .torrents
+            .lock()
+            .expect("Locking seeded torrents")
+            .get(&handshake.info_hash)
+            .cloned()
+            .ok_or(anyhow!("Unknown info hash"))?;
+        println!(
+            "Peer {} connected ({})",
+            address,
+            handshake.peer_id_string()
+        );
+
+        let response = Handshake::new(handshake.info_hash, self.peer_id);
+        writer.write_all(&response.to_bytes()).await?;
+        let bitfield = Message::Bitfield(torrent.have.as_bytes().to_vec());
+        writer.write_all(&bitfield.to_bytes()?).await?;
+
+        // Messages are read in their own task, so that a `cancel` can reach us while blocks are being sent
+        let (sender, receiver) = mpsc::channel(64);
+        let read_task = tokio::spawn(async move {
+            loop {
+                let message = match Message::read_from(&mut reader).await {
+                    // Keep-alive messages are only there to hold the connection open
+                    Ok(Message::KeepAlive) | Err(MessageError::UnknownId(_)) => continue,
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+                if sender.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (choke_sender, choke) = watch::channel(true);
+        let connection = Arc::new(Connection {
+            state: Mutex::new(PeerState::default()),
+            uploaded: AtomicU64::new(0),
+            choke: choke_sender,
+        });
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        self.lock_connections().insert(id, connection.clone());
+
+        let result = self
+            .serve_requests(&torrent, &connection, choke, receiver, &mut writer)
+            .await;
+
+        self.lock_connections().remove(&id);
+        // Give the upload slot of the peer to someone else
+        self.rechoke.notify_one();
+        read_task.abort();
+        result
+    }
+
+    /// Answer the requests of a peer, while the choker lets us upload to it.
+    async fn serve_requests(
+        &self,
+        torrent: &SeededTorrent,
+        connection: &Connection,
+        mut choke: watch::Receiver<bool>,
+        mut receiver: mpsc::Receiver<Message>,
+        writer: &mut OwnedWriteHalf,
+    ) -> Result<(), Error> {
+        let mut queue: VecDeque<Request> = VecDeque::new();
+        loop {
+            tokio::select! {
+                // Choke changes and messages go before the blocks, so that a `cancel` is never late
+                biased;
+
+                changed = choke.changed() => {
+                    changed?;
+                    let choking = *choke.borrow_and_update();
+                    if choking != connection.state().am_choking {
+                        let message = if choking { Message::Choke } else { Message::Unchoke };
+                        writer.write_all(&message.to_bytes()?).await?;
+                        connection.lock_state().on_sent(&message);
+                        // Choking a peer drops the requests it queued
+                        if choking {
+                            queue.clear();
+                        }
+                    }
+                }
+
+                message = receiver.recv() => {
+                    let Some(message) = message else {
+                        return Ok(());
+                    };
+                    connection.lock_state().on_received(&message);
+                    match message {
+                        Message::Interested | Message::NotInterested => self.rechoke.notify_one(),
+                        Message::Request(request)
+                            if !connection.state().am_choking
+                                && queue.len() < MAX_QUEUED_REQUESTS
+                                && is_valid_request(torrent, &request) =>
+                        {
+                            queue.push_back(request);
+                        }
+                        Message::Cancel(request) => {
+                            queue.retain(|queued| *queued != request);
+                        }
+                        // Peers of v2 torrents get the hashes their pieces are checked against from us
+                        Message::HashRequest(request) => {
+                            let answer = match merkle::answer_hash_request(torrent.storage.info(), &request) {
+                                Some(hashes) => Message::Hashes { request, hashes },
+                                None => Message::HashReject(request),
+                            };
+                            writer.write_all(&answer.to_bytes()?).await?;
+                        }
+                        // Other messages don't matter to a seeder
+                        _ => {}
+                    }
+                }
+
+                // Serve the next request once there is nothing more to read for now
+                _ = std::future::ready(()), if !queue.is_empty() => {
+                    let Some(request) = queue.pop_front() else {
+                        continue;
+                    };
+                    // Reading from disk blocks, so it runs on the blocking pool
+                    let storage = torrent.storage.clone();
+                    let block = task::spawn_blocking(move || {
+                        storage.read_block(
+                            request.piece_index as usize,
+                            request.begin as usize,
+                            request.length as usize,
+                        )
+                    })
+                    .await??;
+                    let length = block.len() as u64;
+                    let piece = Message::Piece {
+                        index: request.piece_index,
+                        begin: request.begin,
+                        block,
+                    };
+                    writer.write_all(&piece.to_bytes()?).await?;
+                    connection.uploaded.fetch_add(length, Ordering::Relaxed);
+                    torrent.stats.uploaded.fetch_add(length, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn lock_connections(&self) -> MutexGuard<'_, HashMap<u64, Arc<Connection>>> {
+        self.connections.lock().expect("Locking connections")
+    }
+
+    /// Run the choker: rechoke every [`RECHOKE_INTERVAL`],
+    /// or sooner when a peer changes its interest or leaves.
+    async fn run_choker(self: Arc<Self>) {
+        let mut choker = Choker::default();
+        loop {
+            let _ = timeout(RECHOKE_INTERVAL, self.rechoke.notified()).await;
+            self.rechoke(&mut choker);
+        }
+    }
+
+    /// Pick the peers we upload to.
+    ///
+    /// As a seed, the interested peers we upload the most to keep their slots (tit-for-tat
+    /// for a seed: the fastest downloaders make the best use of our upload).
+    /// One more slot goes to a random interested peer, moved every [`OPTIMISTIC_UNCHOKE_INTERVAL`],
+    /// so that new peers get a chance to show how fast they are.
+    /// @link: https://www.bittorrent.org/beps/bep_0003.html#peer-protocol
+    fn rechoke(&self, choker: &mut Choker) {
+        let connections: Vec<(u64, Arc<Connection>)> = self
+            .lock_connections()
+            .iter()
+            .map(|(id, connection)| (*id, connection.clone()))
+            .collect();
+
+        // Rank the interested peers by what we uploaded to them since the last rechoke
+        let mut interested: Vec<(u64, u64)> = vec![];
+        let mut uploaded = HashMap::new();
+        for (id, connection) in &connections {
+            let total = connection.uploaded.load(Ordering::Relaxed);
+            let last = choker.last_uploaded.get(id).copied().unwrap_or(0);
+            uploaded.insert(*id, total);
+            if connection.state().peer_interested {
+                interested.push((*id, total - last));
+            }
+        }
+        choker.last_uploaded = uploaded;
+        interested.sort_by_key(|(_, rate)| std::cmp::Reverse(*rate));
+
+        let mut unchoked: HashSet<u64> = interested
+            .iter()
+            .take(UPLOAD_SLOTS - 1)
+            .map(|(id, _)| *id)
+            .collect();
+
+        // Move the optimistic unchoke when it is due, or when its peer left or lost interest
+        let candidates: Vec<u64> = interested
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| !unchoked.contains(id))
+            .collect();
+        let rotation_due = choker
+            .last_optimistic
+            .is_none_or(|last| last.elapsed() >= OPTIMISTIC_UNCHOKE_INTERVAL);
+        let still_valid = choker.optimistic.is_some_and(|id| candidates.contains(&id));
+        if rotation_due || !still_valid {
+            choker.optimistic = candidates.choose(&mut rand::rng()).copied();
+            choker.last_optimistic = Some(Instant::now());
+        }
+        unchoked.extend(choker.optimistic);
+
+        for (id, connection) in &connections {
+            let choking = !unchoked.contains(id);
+            connection.choke.send_if_modified(|current| {
+                let modified = *current != choking;
+                *current = choking;
+                modified
+            });
+        }
+    }
+}
+
+fn is_valid_request(torrent: &SeededTorrent, request: &Request) -> bool {
+    request.piece_index >= 0
+        && request.begin >= 0
+        && request.length > 0
+        && request.length <= MAX_REQUEST_LENGTH
+        && torrent.have.has(request.piece_index as usize)
+        && request.begin as usize + request.length as usize
+            <= torrent.storage.piece_len(request.piece_index as usize)
+}
@@ -0,0 +1,391 @@
+use crate::structs::bitfield::Bitfield;
+use crate::structs::torrent::{FileLayout, TorrentInfo};
+use anyhow::{anyhow, Context, Error};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The torrent data on disk.
+///
+/// Pieces are mapped to the files of the torrent, so that a piece can be written
+/// as soon as it is downloaded, even when it spans several files.
+#[derive(Debug)]
+pub struct Storage {
+    layout: Vec<FileLayout>,
+    files: Mutex<Vec<File>>,
+    /// The info dictionary, which has the hashes the pieces are checked against
+    info: TorrentInfo,
+    piece_length: i64,
+    info_hash: [u8; 20],
+    resume_path: PathBuf,
+
+    /// Whether some of the files were already on disk when the storage was opened
+    existing_data: bool,
+}
+
+/// The resume file, saved next to the download and updated after each verified piece.
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeData {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+
+    /// Bitfield of the pieces which were verified and written to disk
+    pieces: ByteBuf,
+
+    files: Vec<ResumeFile>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ResumeFile {
+    path: String,
+    length: i64,
+}
+
+impl Storage {
+    /// Open (or create) the files of the torrent under `output`.
+    pub fn open(info: &TorrentInfo, output: &str) -> Result<Storage, Error> {
+        let layout = info.file_layout(Path::new(output))?;
+        let mut files = Vec::with_capacity(layout.len());
+        let mut existing_data = false;
+
+        for file in &layout {
+            if let Some(parent_dir) = file.path.parent() {
+                fs::create_dir_all(parent_dir)?;
+            }
+            existing_data |= file.path.exists();
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)
+                .with_context(|| format!("Opening {}", file.path.display()))?;
+            handle.set_len(file.length as u64)?;
+            files.push(handle);
+        }
+
+        let resume_path = if info.is_multi_file() {
+            Path::new(output).join(format!(".{}.resume", info.name))
+        } else {
+            PathBuf::from(format!("{}.resume", output))
+        };
+
+        Ok(Storage {
+            layout,
+            files: Mutex::new(files),
+            info: info.clone(),
+            piece_length: info.piece_length as i64,
+            info_hash: info.get_hash(),
+            resume_path,
+            existing_data,
+        })
+    }
+
+    pub fn info(&self) -> &TorrentInfo {
+        &self.info
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.info.piece_count()
+    }
+
+    pub fn piece_len(&self, piece_index: usize) -> usize {
+        self.info.piece_len(piece_index)
+    }
+
+    /// Check some piece data against the hash of the piece.
+    pub fn check_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        self.info.check_piece(piece_index, data)
+    }
+
+    pub fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<(), Error> {
+        if data.len() != self.piece_len(piece_index) {
+            return Err(anyhow!("Invalid length for piece {}", piece_index));
+        }
+        let offset = piece_index as i64 * self.piece_length;
+        self.write_at(offset, data)
+    }
+
+    pub fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>, Error> {
+        let offset = piece_index as i64 * self.piece_length;
+        self.read_at(offset, self.piece_len(piece_index))
+    }
+
+    /// Read a block of `length` bytes, `begin` bytes into the piece.
+    pub fn read_block(
+        &self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        if begin + length > self.piece_len(piece_index) {
+            return Err(anyhow!("Block is out of piece {}", piece_index));
+        }
+        let offset = piece_index as i64 * self.piece_length + begin as i64;
+        self.read_at(offset, length)
+    }
+
+    /// Write `data` at `offset` bytes from the start of the torrent, across as many files as needed.
+    fn write_at(&self, offset: i64, data: &[u8]) -> Result<(), Error> {
+        let mut files = self.files.lock().expect("Locking storage files");
+        for (index, start, range) in self.segments(offset, data.len()) {
+            let file = &mut files[index];
+            file.seek(SeekFrom::Start(start))?;
+            file.write_all(&data[range])
+                .with_context(|| format!("Writing {}", self.layout[index].path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes at `offset` bytes from the start of the torrent, across as many files as needed.
+    fn read_at(&self, offset: i64, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0u8; length];
+        let mut files = self.files.lock().expect("Locking storage files");
+        for (index, start, range) in self.segments(offset, length) {
+            let file = &mut files[index];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut data[range])
+                .with_context(|| format!("Reading {}", self.layout[index].path.display()))?;
+        }
+        Ok(data)
+    }
+
+    /// Split the byte range `offset..offset + length` of the torrent into
+    /// (file index, offset in the file, range in the buffer) segments.
+    fn segments(&self, offset: i64, length: usize) -> Vec<(usize, u64, std::ops::Range<usize>)> {
+        let end = offset + length as i64;
+        self.layout
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < end && offset < file.offset + file.length)
+            .map(|(index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                let range = (start - offset) as usize..(stop - offset) as usize;
+                (index, (start - file.offset) as u64, range)
+            })
+            .collect()
+    }
+
+    /// Find which pieces are already on disk.
+    ///
+    /// Pieces recorded in a matching resume file are re-verified against their hash.
+    /// Without a resume file, every piece of the existing files is checked.
+    pub fn verify(&self) -> Result<Bitfield, Error> {
+        let mut have = Bitfield::new(self.piece_count());
+        let candidates = match self.load_resume() {
+            Some(resume) => resume,
+            None if self.existing_data => {
+                let mut all = Bitfield::new(self.piece_count());
+                (0..self.piece_count()).for_each(|index| all.set(index));
+                all
+            }
+            None => return Ok(have),
+        };
+
+        for piece_index in 0..self.piece_count() {
+            if candidates.has(piece_index) {
+                let data = self.read_piece(piece_index)?;
+                if self.check_piece(piece_index, &data) {
+                    have.set(piece_index);
+                }
+            }
+        }
+        Ok(have)
+    }
+
+    /// Load the bitfield of the resume file, if it belongs to this torrent and file layout.
+    fn load_resume(&self) -> Option<Bitfield> {
+        let bytes = fs::read(&self.resume_path).ok()?;
+        let resume: ResumeData = serde_bencode::from_bytes(&bytes).ok()?;
+        if resume.info_hash.as_slice() != self.info_hash || resume.files != self.resume_files() {
+            return None;
+        }
+        Some(Bitfield::from_bytes(&resume.pieces, self.piece_count()))
+    }
+
+    /// Persist the pieces we have, so that the download can be resumed.
+    pub fn save_resume(&self, have: &Bitfield) -> Result<(), Error> {
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            pieces: ByteBuf::from(have.as_bytes().to_vec()),
+            files: self.resume_files(),
+        };
+        // The pieces must reach the disk before the resume file which records them
+        for file in self.files.lock().expect("Locking storage files").iter() {
+            file.sync_data().context("Syncing torrent data")?;
+        }
+
+        // Write to a temporary file first so that a crash never leaves a truncated resume file
+        let tmp_path = self.resume_path.with_extension("resume.tmp");
+        let mut tmp_file = File::create(&tmp_path).context("Writing resume file")?;
+        tmp_file
+            .write_all(&serde_bencode::to_bytes(&resume)?)
+            .context("Writing resume file")?;
+        tmp_file.sync_all().context("Writing resume file")?;
+        fs::rename(&tmp_path, &self.resume_path).context("Writing resume file")?;
+        Ok(())
+    }
+
+    fn resume_files(&self) -> Vec<ResumeFile> {
+        self.layout
+            .iter()
+            .map(|file| ResumeFile {
+                path: file.path.to_string_lossy().to_string(),
+                length: file.length,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::torrent::TorrentFile;
+    use sha1::{Digest, Sha1};
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    /// A multi-file torrent of 4 pieces, the last one shorter, whose pieces span several files
+    fn torrent() -> (TorrentInfo, Vec<u8>) {
+        let data: Vec<u8> = (0..3 * PIECE_LENGTH + 100)
+            .map(|i| (i as u32).wrapping_mul(7) as u8)
+            .collect();
+        let lengths = [
+            PIECE_LENGTH / 2,
+            PIECE_LENGTH,
+            10,
+            data.len() - PIECE_LENGTH * 3 / 2 - 10,
+        ];
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+            .collect();
+        let info = TorrentInfo {
+            length: None,
+            files: Some(
+                lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(index, length)| TorrentFile {
+                        length: *length as i64,
+                        path: vec!["dir".to_string(), format!("file{}", index)],
+                        attr: None,
+                    })
+                    .collect(),
+            ),
+            name: "storage".to_string(),
+            piece_length: PIECE_LENGTH as i32,
+            pieces: ByteBuf::from(pieces),
+            private: None,
+            source: None,
+            meta_version: None,
+            file_tree: None,
+            piece_layers: Default::default(),
+            raw: vec![],
+        };
+        (info, data)
+    }
+
+    fn open(info: &TorrentInfo, output: &Path) -> Storage {
+        Storage::open(info, output.to_str().unwrap()).unwrap()
+    }
+
+    fn piece(data: &[u8], piece_index: usize) -> &[u8] {
+        data.chunks(PIECE_LENGTH).nth(piece_index).unwrap()
+    }
+
+    #[test]
+    fn pieces_and_blocks_span_files() {
+        let (info, data) = torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&info, dir.path());
+        for piece_index in 0..storage.piece_count() {
+            storage
+                .write_piece(piece_index, piece(&data, piece_index))
+                .unwrap();
+        }
+
+        for piece_index in 0..storage.piece_count() {
+            assert_eq!(
+                storage.read_piece(piece_index).unwrap(),
+                piece(&data, piece_index)
+            );
+        }
+        let block = storage.read_block(1, 100, 200).unwrap();
+        assert_eq!(block, data[PIECE_LENGTH + 100..PIECE_LENGTH + 300]);
+        assert!(storage.read_block(3, 0, 101).is_err());
+        assert!(storage.write_piece(0, &data[..10]).is_err());
+
+        // The files hold the data in order
+        let second = fs::read(dir.path().join("storage").join("dir").join("file1")).unwrap();
+        assert_eq!(second, data[PIECE_LENGTH / 2..PIECE_LENGTH * 3 / 2]);
+    }
+
+    #[test]
+    fn resume_file_is_verified_again() {
+        let (info, data) = torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&info, dir.path());
+        assert!(storage.verify().unwrap().missing().eq(0..4));
+
+        let mut have = Bitfield::new(storage.piece_count());
+        for piece_index in [0, 1, 3] {
+            storage
+                .write_piece(piece_index, piece(&data, piece_index))
+                .unwrap();
+            have.set(piece_index);
+        }
+        storage.save_resume(&have).unwrap();
+        assert!(!storage.resume_path.with_extension("resume.tmp").exists());
+        drop(storage);
+
+        let storage = open(&info, dir.path());
+        assert_eq!(storage.load_resume(), Some(have.clone()));
+        assert_eq!(storage.verify().unwrap(), have);
+
+        // A byte of piece 1 changed on disk: the resume file doesn't vouch for it anymore
+        let path = dir.path().join("storage").join("dir").join("file1");
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[PIECE_LENGTH / 2] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        let verified = storage.verify().unwrap();
+        assert!(verified.has(0) && !verified.has(1) && !verified.has(2) && verified.has(3));
+    }
+
+    #[test]
+    fn resume_file_of_another_layout_is_ignored() {
+        let (info, data) = torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&info, dir.path());
+        storage.write_piece(0, piece(&data, 0)).unwrap();
+        let mut have = Bitfield::new(storage.piece_count());
+        have.set(0);
+        storage.save_resume(&have).unwrap();
+        drop(storage);
+
+        // Same name, different file lengths: the resume file doesn't apply
+        let mut other = info.clone();
+        other.files.as_mut().unwrap()[2].length += 1;
+        let storage = open(&other, dir.path());
+        assert!(storage.resume_path.exists());
+        assert_eq!(storage.load_resume(), None);
+    }
+
+    #[test]
+    fn existing_files_are_verified_without_resume_file() {
+        let (info, data) = torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&info, dir.path());
+        storage.write_piece(2, piece(&data, 2)).unwrap();
+        drop(storage);
+
+        let storage = open(&info, dir.path());
+        assert!(storage.load_resume().is_none());
+        assert!(storage.verify().unwrap().has(2));
+        assert_eq!(storage.verify().unwrap().count(), 1);
+    }
+}
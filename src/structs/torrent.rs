@@ -1,33 +1,177 @@
-use crate::structs::peers::{Peer, PeerList};
-use anyhow::Error;
+use crate::structs::announcer::Announcer;
+use crate::structs::bitfield::Bitfield;
+use crate::structs::dht::Dht;
+use crate::structs::download::{
+    download_from_peer, CompletedPiece, PendingPiece, PickStrategy, PieceQueue,
+};
+use crate::structs::lsd::Lsd;
+use crate::structs::magnet::truncate_v2;
+use crate::structs::merkle;
+use crate::structs::peers::Peer;
+use crate::structs::pex::Swarm;
+use crate::structs::storage::Storage;
+use crate::utils::decoder;
+use crate::utils::trackers::TrackerList;
+use anyhow::{anyhow, Context, Error};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
-use tokio::task::JoinSet;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinSet};
+use tokio::time::timeout;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(dead_code)]
 pub struct Torrent {
     /// URL to a "tracker", which is a central server that keeps track of peers participating in the sharing of a torrent.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
 
+    /// Tiers of backup trackers, used instead of `announce` when present (BEP 12)
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// The program which created the torrent
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+
+    /// When the torrent was created, in seconds since the Unix epoch
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+
+    /// Web seeds: HTTP servers which have the files of the torrent (BEP 19).
+    /// A single URL may be given instead of a list
+    /// @link: https://www.bittorrent.org/beps/bep_0019.html
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "deserialize_url_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+
     /// This maps to a dictionary, with keys described below.
     pub info: TorrentInfo,
+
+    #[serde(skip)]
+    pub stats: Arc<TorrentStats>,
+
+    /// How pieces are picked when downloading
+    #[serde(skip)]
+    pub strategy: PickStrategy,
+
+    /// The trackers we announce to, built from `announce-list` or `announce`
+    #[serde(skip)]
+    pub trackers: Arc<TrackerList>,
+
+    /// Announces the torrent to the trackers, from the first peer lookup until it is no longer seeded
+    #[serde(skip)]
+    pub announcer: Option<Arc<Announcer>>,
+
+    /// The pieces to download, from the selected files. Every piece when `None`
+    #[serde(skip)]
+    pub wanted: Option<Bitfield>,
+
+    /// The DHT node to find peers with, along with the trackers. Never used for private torrents
+    #[serde(skip)]
+    pub dht: Option<Arc<Dht>>,
+
+    /// Finds peers on the local network, along with the trackers. Never used for private torrents
+    #[serde(skip)]
+    pub lsd: Option<Arc<Lsd>>,
 }
 
 #[allow(dead_code)]
 impl Torrent {
-    pub fn check_piece_hash(&self, piece_index: i32, pieces_data: &Vec<u8>) -> bool {
-        let curr_piece = self
-            .info
-            .pieces
-            .chunks(20)
-            .nth(piece_index as usize)
-            .expect("Getting piece");
-        let mut hasher = Sha1::new();
-        hasher.update(&pieces_data);
-        let digest = hasher.finalize();
-        digest.as_slice() == curr_piece
+    /// Parse a `.torrent` file, keeping the raw bytes of its info dictionary for the info hash.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Torrent, Error> {
+        let mut torrent: Torrent =
+            serde_bencode::from_bytes(bytes).context("Parsing torrent file content")?;
+        let raw_info =
+            decoder::raw_dict_value(bytes, b"info")?.ok_or(anyhow!("Missing info dictionary"))?;
+        torrent.info.raw = raw_info.to_vec();
+        torrent.info.check_v2()?;
+        if torrent.info.is_v2() {
+            let layers: PieceLayers =
+                serde_bencode::from_bytes(bytes).context("Parsing piece layers")?;
+            torrent.info.set_piece_layers(layers.piece_layers)?;
+        }
+        torrent.trackers = Arc::new(TrackerList::new(torrent.tiers()));
+        torrent
+            .stats
+            .left
+            .store(torrent.info.len() as u64, Ordering::Relaxed);
+        Ok(torrent)
+    }
+
+    /// Bencode the torrent, to write a `.torrent` file.
+    /// The info dictionary is written from its fields, so keys we don't model are lost.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        serde_bencode::to_bytes(self).context("Bencoding torrent")
+    }
+
+    /// The announcer of the torrent, started on first use. `None` when the torrent has no tracker, DHT nor LSD.
+    pub fn announcer(&mut self) -> Option<Arc<Announcer>> {
+        if self.announcer.is_none() {
+            self.announcer = self.start_announcer();
+        }
+        self.announcer.clone()
+    }
+
+    /// Start a new announcer for the torrent. `None` when the torrent has no tracker, DHT nor LSD.
+    pub fn start_announcer(&self) -> Option<Arc<Announcer>> {
+        // Private torrents only get their peers from their trackers (BEP 27)
+        let dht = self.dht.clone().filter(|_| !self.info.is_private());
+        let lsd = self.lsd.clone().filter(|_| !self.info.is_private());
+        if self.trackers.is_empty() && dht.is_none() && lsd.is_none() {
+            return None;
+        }
+        Some(Arc::new(Announcer::start(
+            self.trackers.clone(),
+            dht,
+            lsd,
+            self.info.get_hash(),
+            self.stats.clone(),
+        )))
+    }
+
+    /// The tiers of trackers of the torrent: `announce-list` if there is one, `announce` otherwise.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.iter().any(|tier| !tier.is_empty()) {
+            return self.announce_list.clone();
+        }
+        if self.announce.is_empty() {
+            return vec![];
+        }
+        vec![vec![self.announce.clone()]]
+    }
+
+    pub fn check_piece_hash(&self, piece_index: i32, pieces_data: &[u8]) -> bool {
+        usize::try_from(piece_index)
+            .is_ok_and(|piece_index| self.info.check_piece(piece_index, pieces_data))
     }
 
     pub fn info_hash_string(&self) -> String {
@@ -38,93 +182,319 @@ impl Torrent {
             .collect::<String>()
     }
 
-    pub async fn get_available_peers(&self) -> Result<Vec<Peer>, Error> {
-        // Step 1: get the peer list from the Tacker
-        let addresses = PeerList::get_peers(self).await?;
+    pub async fn get_available_peers(&mut self) -> Result<Vec<Peer>, Error> {
+        // Step 1: get the peer list from the trackers, with the first announce of the download
+        let announcer = self
+            .announcer()
+            .ok_or(anyhow!("The torrent has no tracker, DHT nor LSD"))?;
+        let mut addresses = announcer
+            .next_peers()
+            .await
+            .ok_or(anyhow!("Announcer stopped"))??;
+        if addresses.is_empty() && self.lsd.is_some() && !self.info.is_private() {
+            // The peers on the local network answer our first LSD announce right away
+            if let Ok(Some(Ok(lan_addresses))) = timeout(LSD_WAIT, announcer.next_peers()).await {
+                addresses = lan_addresses;
+            }
+        }
         let mut available_peers: Vec<Peer> = vec![];
 
-        // Step 2: Get the available peers
+        // Step 2: Get the available peers, connecting to all of them at once
+        let info_hash = self.info.get_hash();
+        let piece_count = self.info.piece_count();
+        let mut join_set = JoinSet::new();
         for address in addresses {
-            let mut peer = Peer::new(address, &self.info.get_hash()).await?;
-            // TODO: improve when the bitfield is implemented
-            peer.get_pieces().await?;
-            // Add if the peer can send pieces.
-            match peer.send_interest().await {
-                Ok(..) => available_peers.push(peer),
-                Err(..) => {}
+            join_set.spawn(async move {
+                let mut peer = Peer::new(address, &info_hash).await?;
+                peer.get_pieces().await?;
+                if peer.pieces(piece_count).count() == 0 {
+                    return Err(anyhow!("Peer has no pieces"));
+                }
+                peer.send_interest().await?;
+                Ok::<Peer, Error>(peer)
+            });
+        }
+
+        // Add if the peer can send pieces.
+        while let Some(result) = join_set.join_next().await {
+            match result.context("Joining peer connection")? {
+                Ok(peer) => available_peers.push(peer),
+                Err(e) => eprintln!("Skipping peer: {:?}", e),
             }
         }
         Ok(available_peers)
     }
 
+    /// Only download the pieces of the files for which `selected` is true, given their index in the torrent.
+    /// Pieces shared with unselected files are downloaded too.
+    pub fn select_files(&mut self, selected: impl Fn(usize) -> bool) {
+        let mut wanted = Bitfield::new(self.info.piece_count());
+        for (index, pieces) in self.info.file_pieces().into_iter().enumerate() {
+            if selected(index) {
+                pieces.for_each(|piece_index| wanted.set(piece_index));
+            }
+        }
+        self.wanted = Some(wanted);
+    }
+
+    fn is_wanted(&self, piece_index: usize) -> bool {
+        self.wanted
+            .as_ref()
+            .is_none_or(|wanted| wanted.has(piece_index))
+    }
+
     pub fn get_piece_len(&self, piece_index: i32) -> i32 {
-        self.info
-            .piece_length
-            .min(self.info.length - piece_index * self.info.piece_length)
+        self.info.piece_len(piece_index as usize) as i32
     }
 
+    /// Download the torrent to `output`, or the pieces of its selected files (see [`Torrent::select_files`]).
+    ///
+    /// Verified pieces are written to disk as soon as they arrive, and recorded in a resume file.
+    /// Pieces already on disk (from a previous run) are verified and not downloaded again.
+    /// The torrent is announced to its trackers meanwhile, and the new peers they give are used too.
     pub async fn download_torrent(
         &mut self,
         peers: Vec<Peer>,
         is_ext: bool,
-    ) -> Result<Vec<Vec<u8>>, Error> {
-        let piece_count = self.info.pieces.chunks(20).len();
-        let mut pieces_result: Vec<Vec<u8>> = vec![vec![]; piece_count];
-        let pending_pieces: Vec<PendingPiece> = (0..piece_count as i32)
+        output: &str,
+    ) -> Result<(), Error> {
+        // Disk accesses run on the blocking pool, so that they don't hold up the peer connections
+        let storage = Arc::new(Storage::open(&self.info, output)?);
+        let verifying = storage.clone();
+        let mut have = task::spawn_blocking(move || verifying.verify())
+            .await?
+            .context("Verifying existing data")?;
+        if have.count() > 0 {
+            println!(
+                "Resuming download: {}/{} pieces already on disk",
+                have.count(),
+                have.len()
+            );
+        }
+
+        let left: i64 = have
+            .missing()
+            .filter(|piece_index| self.is_wanted(*piece_index))
+            .map(|piece_index| self.get_piece_len(piece_index as i32) as i64)
+            .sum();
+        self.stats.left.store(left as u64, Ordering::Relaxed);
+        let announcer = self.announcer();
+
+        let pending = have
+            .missing()
+            .filter(|piece_index| self.is_wanted(*piece_index))
             .map(|piece_index| PendingPiece {
-                piece_index,
-                peers: peers.clone(),
-            })
-            .collect();
+                piece_index: piece_index as i32,
+                piece_len: self.get_piece_len(piece_index as i32),
+                failed_peers: vec![],
+            });
+        let queue = Arc::new(PieceQueue::new(have.len(), pending, self.strategy));
+        let info_hash = self.info.get_hash();
 
+        let mut peers = peers;
         if is_ext {
+            let mut interested = vec![];
             for mut peer in peers {
                 println!(
                     "Sending interest from peer ID {} | {}",
                     peer.peer_id, peer.address,
                 );
-                peer.send_interest().await.expect("Sending interest");
+                match peer.send_interest().await {
+                    Ok(()) => interested.push(peer),
+                    Err(e) => eprintln!("Skipping peer {}: {:?}", peer.address, e),
+                }
             }
+            peers = interested;
         }
-        let spawn = |join_set: &mut JoinSet<_>, pending_piece: PendingPiece| {
-            let piece_len = self.get_piece_len(pending_piece.piece_index);
-            join_set.spawn(async move {
-                let mut piece_data = vec![];
-                for mut peer in pending_piece.peers {
-                    if let Ok(data) = peer
-                        .download_piece(pending_piece.piece_index, piece_len)
-                        .await
-                    {
-                        piece_data = data;
-                        break;
+
+        // Peers exchange the peers they know, except for private torrents (BEP 27)
+        let (discovered_sender, mut discovered) = mpsc::unbounded_channel();
+        let swarm = (!self.info.is_private()).then(|| Arc::new(Swarm::new(discovered_sender)));
+
+        // One worker per peer, each sending the pieces it downloads back here to be verified
+        let (completed_sender, mut completed) = mpsc::channel(16);
+        let mut connected: HashSet<SocketAddr> = peers.iter().map(|peer| peer.address).collect();
+        for peer in peers {
+            tokio::spawn(download_from_peer(
+                peer,
+                queue.clone(),
+                completed_sender.clone(),
+                swarm.clone(),
+            ));
+        }
+        // Peers from later announces are added as long as a worker is still running
+        let workers = completed_sender.downgrade();
+        drop(completed_sender);
+        let next_peers = || async {
+            match &announcer {
+                Some(announcer) => announcer.next_peers().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let mut connect = |address: SocketAddr| {
+            let Some(sender) = workers.upgrade() else {
+                return;
+            };
+            if connected.insert(address) {
+                tokio::spawn(connect_and_download(
+                    address,
+                    info_hash,
+                    queue.clone(),
+                    sender,
+                    swarm.clone(),
+                ));
+            }
+        };
+
+        // Number of corrupt pieces sent by each peer
+        let mut strikes: HashMap<SocketAddr, u32> = HashMap::new();
+
+        loop {
+            let CompletedPiece {
+                mut piece,
+                peers,
+                data,
+            } = tokio::select! {
+                piece = completed.recv() => match piece {
+                    Some(piece) => piece,
+                    None => break,
+                },
+                Some(Ok(addresses)) = next_peers() => {
+                    addresses.into_iter().for_each(&mut connect);
+                    continue;
+                }
+                Some(address) = discovered.recv() => {
+                    connect(address);
+                    continue;
+                }
+            };
+
+            let index = piece.piece_index as usize;
+            if self.check_piece_hash(piece.piece_index, &data) {
+                let length = data.len() as u64;
+                have.set(index);
+                let (writing, saved) = (storage.clone(), have.clone());
+                task::spawn_blocking(move || {
+                    writing.write_piece(index, &data)?;
+                    writing.save_resume(&saved)
+                })
+                .await??;
+                queue.complete();
+                self.stats.downloaded.fetch_add(length, Ordering::Relaxed);
+                self.stats.left.fetch_sub(length, Ordering::Relaxed);
+                if have.is_complete() {
+                    if let Some(announcer) = &announcer {
+                        announcer.completed();
                     }
+                    break;
                 }
-                (pending_piece.piece_index, piece_data)
-            });
-        };
+                if !have.missing().any(|index| self.is_wanted(index)) {
+                    break;
+                }
+                continue;
+            }
 
-        let mut join_set = JoinSet::new();
-        for pending_piece in pending_pieces {
-            spawn(&mut join_set, pending_piece);
-        }
+            let hash_failures = self.stats.hash_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            eprintln!(
+                "Invalid hash for piece {} from {:?} ({} hash failures so far)",
+                index, peers, hash_failures
+            );
 
-        while let Some(result) = join_set.join_next().await {
-            if let Ok((index, data)) = result {
-                if data.len() == 0 {
-                    eprintln!("Error downloading piece. Index: {}", index);
-                } else {
-                    pieces_result[index as usize] = data;
+            // In endgame mode, every peer which sent a block of the piece is suspect
+            for address in peers {
+                let peer_strikes = strikes.entry(address).or_default();
+                *peer_strikes += 1;
+                if *peer_strikes >= MAX_PEER_HASH_FAILURES {
+                    eprintln!("Dropping peer {} for sending corrupt data", address);
+                    queue.ban(address);
                 }
+
+                // Give the piece to the peers which haven't sent us corrupt data for it yet
+                piece.failed_peers.push(address);
             }
+            queue.push(piece);
         }
 
-        Ok(pieces_result)
+        let missing = have
+            .missing()
+            .filter(|piece_index| self.is_wanted(*piece_index))
+            .count();
+        if missing > 0 {
+            return Err(anyhow!(
+                "{} pieces are missing ({} hash failures), download can be resumed",
+                missing,
+                self.stats.hash_failures.load(Ordering::Relaxed)
+            ));
+        }
+        println!(
+            "Download complete ({} hash failures)",
+            self.stats.hash_failures.load(Ordering::Relaxed)
+        );
+        Ok(())
+    }
+}
+
+/// Connect to a peer given by a tracker or another peer during the download, and download from it.
+async fn connect_and_download(
+    address: SocketAddr,
+    info_hash: [u8; 20],
+    queue: Arc<PieceQueue>,
+    completed: mpsc::Sender<CompletedPiece>,
+    swarm: Option<Arc<Swarm>>,
+) {
+    match Peer::new(address, &info_hash).await {
+        Ok(peer) => download_from_peer(peer, queue, completed, swarm).await,
+        Err(e) => eprintln!("Skipping peer {}: {:?}", address, e),
+    }
+}
+
+/// Read `url-list`, which is either a list of URLs or a single one.
+fn deserialize_url_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
     }
+    Ok(match UrlList::deserialize(deserializer)? {
+        UrlList::One(url) if url.is_empty() => vec![],
+        UrlList::One(url) => vec![url],
+        UrlList::Many(urls) => urls,
+    })
 }
 
-struct PendingPiece {
-    piece_index: i32,
-    peers: Vec<Peer>,
+/// The `piece layers` of a v2 torrent, next to its info dictionary:
+/// the piece layer of each file longer than a piece, by the `pieces root` of the file
+#[derive(Debug, Deserialize)]
+struct PieceLayers {
+    #[serde(rename = "piece layers", default)]
+    piece_layers: HashMap<ByteBuf, ByteBuf>,
+}
+
+/// Number of corrupt pieces after which a peer is dropped
+const MAX_PEER_HASH_FAILURES: u32 = 3;
+
+/// How long we wait for peers on the local network when the other sources have none
+const LSD_WAIT: Duration = Duration::from_secs(3);
+
+/// Counters about the transfers of a torrent.
+#[derive(Debug, Default)]
+pub struct TorrentStats {
+    /// Number of downloaded pieces which didn't match their SHA-1 hash
+    pub hash_failures: AtomicU64,
+
+    /// Number of bytes sent to other peers
+    pub uploaded: AtomicU64,
+
+    /// Number of bytes of verified pieces downloaded since the torrent was loaded
+    pub downloaded: AtomicU64,
+
+    /// Number of bytes we still need to download
+    pub left: AtomicU64,
 }
 
 #[allow(dead_code)]
@@ -132,7 +502,13 @@ struct PendingPiece {
 pub struct TorrentInfo {
     /// The length of the file, in bytes.
     /// For single-file torrents only (length is only present when the download represents a single file)
-    pub length: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<i64>,
+
+    /// The list of files, for multi-file torrents only (files is only present when the download represents a directory)
+    /// @link: https://www.bittorrent.org/beps/bep_0003.html#info-dictionary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<TorrentFile>>,
 
     /// The name key maps to a UTF-8 encoded string which is the suggested name to save the file (or directory) as. It is purely advisory
     /// @link: https://www.bittorrent.org/beps/bep_0003.html#info-dictionary
@@ -156,22 +532,498 @@ pub struct TorrentInfo {
     /// each of which is the SHA1 hash of the piece at the corresponding index.
     ///
     /// Every 20 bytes of this string is the SHA1 hash (or `&[u8]` chunk of length `20`) of a piece.
+    /// Empty for v2-only torrents, whose pieces are checked against the merkle trees of their files.
+    #[serde(default, skip_serializing_if = "is_empty_bytes")]
     pub pieces: ByteBuf,
+
+    /// 2 for v2 and hybrid torrents, which have a `file tree`
+    /// @link: https://www.bittorrent.org/beps/bep_0052.html#info-dictionary
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<i64>,
+
+    /// The files of v2 and hybrid torrents, with the root of their merkle tree
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+
+    /// When 1, peers must only be found through the trackers of the torrent
+    /// @link: https://www.bittorrent.org/beps/bep_0027.html
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+
+    /// The tracker or site the torrent was made for. It only makes the info hash unique to it,
+    /// so that a torrent can be seeded to several private trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// The bencoded info dictionary, as it was received.
+    /// It holds the keys we don't model, which are needed to compute the info hash.
+    #[serde(skip)]
+    pub raw: Vec<u8>,
+
+    /// The piece layer of each file longer than a piece of a v2 torrent, by `pieces root`.
+    /// They come from the `piece layers` of the torrent file, or from peers for magnet links.
+    #[serde(skip)]
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
+}
+
+/// A file entry of a multi-file torrent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TorrentFile {
+    /// The length of the file, in bytes.
+    pub length: i64,
+
+    /// A list of UTF-8 encoded strings corresponding to subdirectory names,
+    /// the last of which is the actual file name (a zero length list is an error case).
+    pub path: Vec<String>,
+
+    /// File attributes (BEP 47): `p` marks the padding files of hybrid torrents,
+    /// which align the other files on pieces and are never written to disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl TorrentFile {
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// A directory of the `file tree` of a v2 torrent: each name maps to a file or a subdirectory.
+/// @link: https://www.bittorrent.org/beps/bep_0052.html#info-dictionary
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FileTree(pub BTreeMap<String, FileTreeEntry>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeEntry {
+    /// A file is a dictionary with a single empty key
+    File {
+        #[serde(rename = "")]
+        file: FileTreeFile,
+    },
+    Directory(FileTree),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileTreeFile {
+    pub length: i64,
+
+    /// The root of the merkle tree of the file, absent for empty files
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+/// A file of a v2 torrent, out of its `file tree`
+#[derive(Debug, Clone)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: i64,
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+impl FileTree {
+    /// The files of the tree, in the order of their paths, which is the order of their pieces.
+    pub fn files(&self) -> Vec<V2File> {
+        let mut files = vec![];
+        self.collect_files(&mut vec![], &mut files);
+        files
+    }
+
+    fn collect_files(&self, path: &mut Vec<String>, files: &mut Vec<V2File>) {
+        for (name, entry) in &self.0 {
+            path.push(name.clone());
+            match entry {
+                FileTreeEntry::File { file } => files.push(V2File {
+                    path: path.clone(),
+                    length: file.length,
+                    pieces_root: file
+                        .pieces_root
+                        .as_ref()
+                        .and_then(|root| root.as_slice().try_into().ok()),
+                }),
+                FileTreeEntry::Directory(directory) => directory.collect_files(path, files),
+            }
+            path.pop();
+        }
+    }
+}
+
+/// Where a file of the torrent lives on disk, and which bytes of the torrent it holds.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub path: PathBuf,
+
+    /// The length of the file, in bytes.
+    pub length: i64,
+
+    /// Offset of the first byte of the file, counted from the start of the torrent data.
+    pub offset: i64,
 }
 
 impl TorrentInfo {
-    pub fn len(&self) -> i32 {
-        // For single files, this will do, but for multiple files, we need to iterate over the files and sum their lengths
-        self.length
+    /// Parse a bencoded info dictionary, keeping its raw bytes for the info hash.
+    pub fn from_bytes(bytes: &[u8]) -> Result<TorrentInfo, Error> {
+        let mut info: TorrentInfo =
+            serde_bencode::from_bytes(bytes).context("Decoding torrent info")?;
+        info.raw = bytes.to_vec();
+        info.check_v2()?;
+        Ok(info)
+    }
+
+    /// Whether the torrent has a v2 `file tree` (BEP 52), along with v1 `pieces` or not.
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    /// Whether the torrent has v1 `pieces`, which we then check pieces against, hybrid torrents included.
+    pub fn has_v1(&self) -> bool {
+        !self.is_v2() || self.length.is_some() || self.files.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v2() && self.has_v1()
+    }
+
+    /// The files of a v2 torrent, empty for v1 torrents.
+    pub fn v2_files(&self) -> Vec<V2File> {
+        self.file_tree
+            .as_ref()
+            .map(FileTree::files)
+            .unwrap_or_default()
     }
 
+    /// Check what v2 torrents require: a piece length of at least 16 KiB which is a power of two,
+    /// and a valid `pieces root` for each non-empty file.
+    fn check_v2(&self) -> Result<(), Error> {
+        if !self.is_v2() {
+            return Ok(());
+        }
+        let piece_length = self.piece_length as usize;
+        if piece_length < merkle::MERKLE_BLOCK_SIZE || !piece_length.is_power_of_two() {
+            return Err(anyhow!(
+                "Invalid piece length {} for a v2 torrent",
+                self.piece_length
+            ));
+        }
+        for file in self.v2_files() {
+            if file.path.iter().any(|component| component.is_empty()) || file.length < 0 {
+                return Err(anyhow!("Invalid file {:?} in file tree", file.path));
+            }
+            if file.length > 0 && file.pieces_root.is_none() {
+                return Err(anyhow!("Invalid pieces root for file {:?}", file.path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the piece layers of a v2 torrent, checking each of them against the root of its file.
+    pub fn set_piece_layers(&mut self, layers: HashMap<ByteBuf, ByteBuf>) -> Result<(), Error> {
+        for (root, layer) in layers {
+            let Ok(root) = <[u8; 32]>::try_from(root.as_slice()) else {
+                return Err(anyhow!("Invalid pieces root in piece layers"));
+            };
+            if !layer.len().is_multiple_of(32) {
+                return Err(anyhow!("Invalid piece layer for {}", hex::encode(root)));
+            }
+            let layer: Vec<[u8; 32]> = layer
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().expect("Chunk of 32 bytes"))
+                .collect();
+            self.add_piece_layer(root, layer)?;
+        }
+        Ok(())
+    }
+
+    /// Keep the piece layer of the file with this `pieces root`, if it matches the root.
+    pub fn add_piece_layer(&mut self, root: [u8; 32], layer: Vec<[u8; 32]>) -> Result<(), Error> {
+        let piece_length = self.piece_length as usize;
+        let Some(file) = self
+            .v2_files()
+            .into_iter()
+            .find(|file| file.pieces_root == Some(root))
+        else {
+            // Layers of files we don't have don't matter
+            return Ok(());
+        };
+        let piece_count = (file.length as usize).div_ceil(piece_length);
+        if layer.len() != piece_count || merkle::layer_root(&layer, piece_length) != root {
+            return Err(anyhow!(
+                "Piece layer of {} doesn't match its pieces root",
+                file.path.join("/")
+            ));
+        }
+        self.piece_layers.insert(root, layer);
+        Ok(())
+    }
+
+    /// The files of a v2 torrent which are longer than a piece, and whose piece layer we don't have yet.
+    /// Their pieces can't be checked until we get it.
+    pub fn missing_piece_layers(&self) -> Vec<V2File> {
+        if self.has_v1() {
+            return vec![];
+        }
+        self.v2_files()
+            .into_iter()
+            .filter(|file| file.length > self.piece_length as i64)
+            .filter(|file| {
+                file.pieces_root
+                    .is_some_and(|root| !self.piece_layers.contains_key(&root))
+            })
+            .collect()
+    }
+
+    /// Total length of the torrent data, in bytes, padding files included.
+    pub fn len(&self) -> i64 {
+        if !self.has_v1() {
+            return self.v2_files().iter().map(|file| file.length).sum();
+        }
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.length.unwrap_or(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if !self.has_v1() {
+            let piece_length = self.piece_length as usize;
+            return self
+                .v2_files()
+                .iter()
+                .map(|file| (file.length as usize).div_ceil(piece_length))
+                .sum();
+        }
+        self.pieces.len() / 20
+    }
+
+    /// The pieces each file of the torrent lies in, by file index: the index of the file in `files`,
+    /// or in the file tree of v2-only torrents. Empty files lie in no piece.
+    pub fn file_pieces(&self) -> Vec<Range<usize>> {
+        let piece_length = self.piece_length as usize;
+        if !self.has_v1() {
+            let mut first_piece = 0;
+            return self
+                .v2_files()
+                .iter()
+                .map(|file| {
+                    let start = first_piece;
+                    first_piece += (file.length as usize).div_ceil(piece_length);
+                    start..first_piece
+                })
+                .collect();
+        }
+        let lengths = match &self.files {
+            Some(files) => files.iter().map(|file| file.length as usize).collect(),
+            None => vec![self.len() as usize],
+        };
+        let mut offset = 0;
+        lengths
+            .into_iter()
+            .map(|length| {
+                let start = offset;
+                offset += length;
+                if length == 0 {
+                    return start / piece_length..start / piece_length;
+                }
+                start / piece_length..offset.div_ceil(piece_length)
+            })
+            .collect()
+    }
+
+    /// The length of a piece: every piece is `piece length` long, except the last one of the torrent.
+    /// Files of v2-only torrents start on a piece, so the last piece of each of their files may be shorter.
+    pub fn piece_len(&self, piece_index: usize) -> usize {
+        let piece_length = self.piece_length as i64;
+        let (length, piece) = if self.has_v1() {
+            (self.len(), piece_index)
+        } else {
+            match self.v2_piece(piece_index) {
+                Some((file, piece)) => (file.length, piece),
+                None => return 0,
+            }
+        };
+        piece_length
+            .min(length - piece as i64 * piece_length)
+            .max(0) as usize
+    }
+
+    /// The file of a v2 torrent a piece belongs to, and the index of the piece in the file.
+    fn v2_piece(&self, piece_index: usize) -> Option<(V2File, usize)> {
+        let piece_length = self.piece_length as usize;
+        let mut first_piece = 0;
+        for file in self.v2_files() {
+            let pieces = (file.length as usize).div_ceil(piece_length);
+            if piece_index < first_piece + pieces {
+                return Some((file, piece_index - first_piece));
+            }
+            first_piece += pieces;
+        }
+        None
+    }
+
+    /// Check a piece against its hash: the SHA-1 hash of `pieces` when the torrent has them,
+    /// the merkle tree of its file for v2-only torrents.
+    pub fn check_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        if data.len() != self.piece_len(piece_index) {
+            return false;
+        }
+        if self.has_v1() {
+            let Some(expected) = self.pieces.chunks_exact(20).nth(piece_index) else {
+                return false;
+            };
+            return Sha1::digest(data).as_slice() == expected;
+        }
+
+        let piece_length = self.piece_length as usize;
+        let Some((file, piece)) = self.v2_piece(piece_index) else {
+            return false;
+        };
+        let Some(root) = file.pieces_root else {
+            return false;
+        };
+        if file.length as usize <= piece_length {
+            return merkle::file_root(data) == root;
+        }
+        self.piece_layers
+            .get(&root)
+            .and_then(|layer| layer.get(piece))
+            .is_some_and(|expected| merkle::piece_hash(data, piece_length) == *expected)
+    }
+
+    /// Lay the files of the torrent out under `output`.
+    ///
+    /// Single-file torrents are written to `output` itself, while multi-file torrents
+    /// are written in the `output/<name>` directory, following each file's path.
+    ///
+    /// The padding files of hybrid torrents are left out, their bytes are zeros.
+    /// The files of v2-only torrents each start on a piece.
+    pub fn file_layout(&self, output: &Path) -> Result<Vec<FileLayout>, Error> {
+        if !self.has_v1() {
+            return self.v2_file_layout(output);
+        }
+        let Some(files) = &self.files else {
+            return Ok(vec![FileLayout {
+                path: output.to_path_buf(),
+                length: self.len(),
+                offset: 0,
+            }]);
+        };
+
+        let root = output.join(sanitize_component(&self.name)?);
+        let mut offset = 0;
+        let mut layout = Vec::with_capacity(files.len());
+        for file in files {
+            if file.path.is_empty() {
+                return Err(anyhow!("Empty file path in torrent"));
+            }
+            if !file.is_padding() {
+                layout.push(FileLayout {
+                    path: file_path(&root, &file.path)?,
+                    length: file.length,
+                    offset,
+                });
+            }
+            offset += file.length;
+        }
+        Ok(layout)
+    }
+
+    fn v2_file_layout(&self, output: &Path) -> Result<Vec<FileLayout>, Error> {
+        let files = self.v2_files();
+        // A single file at the top of the tree is written to `output`, as for v1 single-file torrents
+        if let [file] = files.as_slice() {
+            if file.path.len() == 1 {
+                return Ok(vec![FileLayout {
+                    path: output.to_path_buf(),
+                    length: file.length,
+                    offset: 0,
+                }]);
+            }
+        }
+
+        let root = output.join(sanitize_component(&self.name)?);
+        let piece_length = self.piece_length as i64;
+        let mut offset = 0;
+        let mut layout = Vec::with_capacity(files.len());
+        for file in files {
+            layout.push(FileLayout {
+                path: file_path(&root, &file.path)?,
+                length: file.length,
+                offset,
+            });
+            offset += (file.length + piece_length - 1) / piece_length * piece_length;
+        }
+        Ok(layout)
+    }
+
+    /// The info hash used with peers, trackers and the DHT: SHA-1 of the bencoded info dictionary,
+    /// or its v2 info hash truncated to 20 bytes for v2-only torrents.
     pub fn get_hash(&self) -> [u8; 20] {
-        let code = serde_bencode::to_bytes(&self).expect("Bencoding the info section");
-        let mut hasher = Sha1::new();
-        hasher.update(&code.as_slice());
-        hasher
-            .finalize()
-            .try_into()
-            .expect("Converting digest to [u8; 20] array")
+        if let (false, Some(info_hash_v2)) = (self.has_v1(), self.info_hash_v2()) {
+            return truncate_v2(&info_hash_v2);
+        }
+        Sha1::digest(self.bencoded()).into()
+    }
+
+    /// SHA-256 of the bencoded info dictionary, for v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.is_v2().then(|| Sha256::digest(self.bencoded()).into())
+    }
+
+    /// The bencoded info dictionary.
+    /// The raw bytes are used when available, since re-encoding would drop the keys we don't model.
+    fn bencoded(&self) -> Vec<u8> {
+        if self.raw.is_empty() {
+            serde_bencode::to_bytes(&self).expect("Bencoding the info section")
+        } else {
+            self.raw.clone()
+        }
+    }
+}
+
+fn is_empty_bytes(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
+}
+
+/// The path of a file of a multi-file torrent, under the `root` directory of the torrent.
+fn file_path(root: &Path, components: &[String]) -> Result<PathBuf, Error> {
+    let mut path = root.to_path_buf();
+    for component in components {
+        path.push(sanitize_component(component)?);
+    }
+    Ok(path)
+}
+
+/// Make sure a path component coming from a torrent can't escape the output directory.
+fn sanitize_component(component: &str) -> Result<&str, Error> {
+    if component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains(['/', '\\'])
+    {
+        return Err(anyhow!(
+            "Invalid path component in torrent: {:?}",
+            component
+        ));
     }
+    Ok(component)
 }
@@ -1,3 +1,4 @@
 pub mod decoder;
 pub mod files;
 pub mod trackers;
+pub mod udp_tracker;
@@ -1,3 +1,4 @@
+use anyhow::{anyhow, Error};
 use serde_json::{Map, Value};
 
 #[allow(dead_code)]
@@ -67,3 +68,176 @@ pub fn decode_bencoded_value(encoded_value: &str) -> (Value, &str) {
 
     panic!("Unhandled encoded value: {}", encoded_value)
 }
+
+/// Find where the bencoded value starting at `start` ends.
+/// Returns the offset of the first byte after the value, so that `&bytes[start..end]` is the raw value.
+pub fn bencoded_value_end(bytes: &[u8], start: usize) -> Result<usize, Error> {
+    let mut pos = start;
+    // Nesting is tracked with a counter instead of recursion, so deeply nested input can't overflow the stack.
+    let mut depth = 0usize;
+    loop {
+        match bytes.get(pos) {
+            Some(b'd') | Some(b'l') => {
+                depth += 1;
+                pos += 1;
+                continue;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            Some(b'i') => {
+                let end = bytes[pos..]
+                    .iter()
+                    .position(|b| *b == b'e')
+                    .ok_or(anyhow!("Unterminated integer at offset {}", pos))?;
+                pos += end + 1;
+            }
+            Some(b'0'..=b'9') => {
+                let colon = bytes[pos..]
+                    .iter()
+                    .position(|b| *b == b':')
+                    .ok_or(anyhow!("Invalid string length at offset {}", pos))?;
+                let len: usize = std::str::from_utf8(&bytes[pos..pos + colon])?.parse()?;
+                pos += colon + 1;
+                if bytes.len() - pos < len {
+                    return Err(anyhow!("String at offset {} is out of bounds", pos));
+                }
+                pos += len;
+            }
+            _ => return Err(anyhow!("Invalid bencoded value at offset {}", pos)),
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Get the raw bencoded value stored under `key` in the bencoded dictionary `bytes`, exactly as it was encoded.
+pub fn raw_dict_value<'a>(bytes: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, Error> {
+    if bytes.first() != Some(&b'd') {
+        return Err(anyhow!("Expected a bencoded dictionary"));
+    }
+
+    let mut pos = 1;
+    while bytes.get(pos) != Some(&b'e') {
+        let key_end = bencoded_value_end(bytes, pos)?;
+        let value_end = bencoded_value_end(bytes, key_end)?;
+        let raw_key = &bytes[pos..key_end];
+        let colon = raw_key
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(anyhow!("Dictionary keys must be strings"))?;
+        if &raw_key[colon + 1..] == key {
+            return Ok(Some(&bytes[key_end..value_end]));
+        }
+        pos = value_end;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::torrent::Torrent;
+    use sha1::{Digest, Sha1};
+
+    /// An info dictionary with keys we don't model: `md5sum` in a file, and `x-source` at the top
+    fn info_dict() -> Vec<u8> {
+        let mut info = b"d5:filesld6:lengthi5e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl1:aeed6:lengthi3e4:pathl3:dir1:beee4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend([0xab; 20]);
+        info.extend(b"8:x-sourced3:urli7e5:valuel1:xi-3eeee");
+        info
+    }
+
+    fn torrent_file() -> Vec<u8> {
+        let mut torrent = b"d8:announce30:http://tracker.example.com/ann4:info".to_vec();
+        torrent.extend(info_dict());
+        torrent.extend(b"e");
+        torrent
+    }
+
+    #[test]
+    fn value_ends() {
+        assert_eq!(bencoded_value_end(b"i42e", 0).unwrap(), 4);
+        assert_eq!(bencoded_value_end(b"i-42eXX", 0).unwrap(), 5);
+        assert_eq!(bencoded_value_end(b"4:spam", 0).unwrap(), 6);
+        assert_eq!(bencoded_value_end(b"0:", 0).unwrap(), 2);
+        assert_eq!(bencoded_value_end(b"le", 0).unwrap(), 2);
+        assert_eq!(bencoded_value_end(b"d1:ali1e2:bce1:bi2ee", 0).unwrap(), 20);
+        // Starting in the middle of the input, as for the values of a dictionary
+        let dict = b"d3:fooli1ei2ee3:bar0:e";
+        assert_eq!(bencoded_value_end(dict, 6).unwrap(), 14);
+        assert_eq!(bencoded_value_end(dict, 14).unwrap(), 19);
+        assert_eq!(bencoded_value_end(dict, 0).unwrap(), dict.len());
+
+        // Deep nesting doesn't overflow the stack
+        let mut nested = vec![b'l'; 100_000];
+        nested.extend(vec![b'e'; 100_000]);
+        assert_eq!(bencoded_value_end(&nested, 0).unwrap(), nested.len());
+    }
+
+    #[test]
+    fn invalid_values_have_no_end() {
+        for invalid in [
+            &b""[..],
+            b"e",
+            b"x",
+            b"i42",
+            b"5:spam",
+            b"4spam",
+            b"-1:a",
+            b"l4:spam",
+            b"d3:fooi1e",
+            b"99999999999999999999999:a",
+        ] {
+            assert!(
+                bencoded_value_end(invalid, 0).is_err(),
+                "{}",
+                String::from_utf8_lossy(invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn raw_dictionary_values() {
+        let dict = b"d3:foo4:info4:infod1:ai1e1:bl0:ee5:otheri3ee";
+        // The key is matched, not a value which looks like it
+        assert_eq!(
+            raw_dict_value(dict, b"info").unwrap(),
+            Some(&b"d1:ai1e1:bl0:ee"[..])
+        );
+        assert_eq!(raw_dict_value(dict, b"foo").unwrap(), Some(&b"4:info"[..]));
+        assert_eq!(raw_dict_value(dict, b"other").unwrap(), Some(&b"i3e"[..]));
+        assert_eq!(raw_dict_value(dict, b"missing").unwrap(), None);
+        assert_eq!(raw_dict_value(b"de", b"info").unwrap(), None);
+
+        assert!(raw_dict_value(b"l4:infoe", b"info").is_err());
+        assert!(raw_dict_value(b"di1ei2ee", b"info").is_err());
+        assert!(raw_dict_value(b"d4:info", b"info").is_err());
+    }
+
+    #[test]
+    fn info_hash_covers_unmodeled_keys() {
+        let info = info_dict();
+        let torrent_file = torrent_file();
+        assert_eq!(
+            raw_dict_value(&torrent_file, b"info").unwrap(),
+            Some(info.as_slice())
+        );
+
+        let torrent = Torrent::from_bytes(&torrent_file).unwrap();
+        let expected: [u8; 20] = Sha1::digest(&info).into();
+        assert_eq!(torrent.info.get_hash(), expected);
+        assert_eq!(torrent.info.files.as_ref().map(Vec::len), Some(2));
+
+        // Re-encoding the modeled fields would drop `md5sum` and `x-source`, and change the hash
+        let reencoded = serde_bencode::to_bytes(&torrent.info).unwrap();
+        assert_ne!(reencoded, info);
+        assert_ne!(<[u8; 20]>::from(Sha1::digest(&reencoded)), expected);
+
+        // Parsing the torrent again gives the same info hash
+        let again = Torrent::from_bytes(&torrent_file).unwrap();
+        assert_eq!(again.info.get_hash(), expected);
+    }
+}
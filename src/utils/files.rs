@@ -3,14 +3,14 @@ use std::fs;
 use std::io::Write;
 use std::path::Path;
 
-pub fn write_file(file_path: &str, data: &[u8]) -> Result<(), Error> {
+pub fn write_file<P: AsRef<Path>>(file_path: P, data: &[u8]) -> Result<(), Error> {
     // Check that the directory exists
-    let parent_dir = Path::new(file_path).parent();
-    if Some(parent_dir) != None {
-        fs::create_dir_all(parent_dir.unwrap())?;
+    if let Some(parent_dir) = file_path.as_ref().parent() {
+        fs::create_dir_all(parent_dir)?;
     }
 
     let mut file = fs::File::create(file_path).context("Creating file")?;
     file.write_all(data).context("Writing to file")?;
     Ok(())
 }
+
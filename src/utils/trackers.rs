@@ -1,9 +1,17 @@
 use crate::structs::peers::PeerList;
-use reqwest::{Client, Error, Url};
+use crate::utils::udp_tracker::UdpTracker;
+use anyhow::{anyhow, Context, Error};
+use rand::seq::SliceRandom;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::timeout;
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct QueryParams {
     /// the info hash of the torrent
     /// 20 bytes long, will need to be URL encoded
@@ -34,39 +42,431 @@ pub struct QueryParams {
     /// For the purposes of this challenge, set this to 1.
     /// The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// The `tracker id` the tracker gave in a previous response, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+
+    /// Why we announce: left out for the regular announces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<AnnounceEvent>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    /// The first announce of a download
+    Started,
+
+    /// The download just finished
+    Completed,
+
+    /// We are leaving the swarm
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// The value of the event in UDP announce requests, where 0 means no event.
+    pub fn udp_id(&self) -> u32 {
+        match self {
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct TrackerResponse {
+    /// Why the announce failed: when present, no other key is expected
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+
+    /// The announce went through, but the tracker has something to say about it
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
+
     /// An integer, indicating how often your client should make a request to the tracker.
     pub interval: Option<u64>,
 
-    /// A string, which contains list of peers that your client can connect to.
-    /// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    /// Announces must not be more frequent than this, in seconds
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<u64>,
+
+    /// To send back with the next announces to this tracker
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<String>,
+
+    /// Number of peers with the whole torrent (seeders)
+    pub complete: Option<u64>,
+
+    /// Number of peers still downloading (leechers)
+    pub incomplete: Option<u64>,
+
+    /// The peers that your client can connect to.
+    /// Either a string where each peer is represented using 6 bytes (the first 4 bytes are the peer's IP address
+    /// and the last 2 bytes are the peer's port number), or a list of dictionaries with `peer id`, `ip` and `port` keys.
     pub peers: Option<PeerList>,
+
+    /// The IPv6 peers, in the compact form only: 18 bytes per peer, the IP address then the port
+    /// @link: https://www.bittorrent.org/beps/bep_0007.html
+    #[serde(default, deserialize_with = "PeerList::deserialize_v6")]
+    pub peers6: Option<PeerList>,
+}
+
+impl TrackerResponse {
+    /// Parse the bencoded response of an HTTP tracker. A failure reason is turned into an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<TrackerResponse, Error> {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(bytes).context("Parsing tracker response")?;
+        if let Some(failure_reason) = response.failure_reason {
+            return Err(anyhow!("Tracker failure: {}", failure_reason));
+        }
+        if let Some(warning_message) = &response.warning_message {
+            eprintln!("Tracker warning: {}", warning_message);
+        }
+        Ok(response)
+    }
+
+    /// The IPv4 and IPv6 peers of the response.
+    pub fn addresses(self) -> Vec<SocketAddr> {
+        self.peers
+            .into_iter()
+            .chain(self.peers6)
+            .flat_map(|peers| peers.0)
+            .collect()
+    }
+}
+
+/// Statistics of a torrent, from a tracker scrape
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ScrapeFile {
+    /// Number of peers with the whole torrent (seeders)
+    pub complete: u64,
+
+    /// Number of times the torrent was downloaded
+    pub downloaded: u64,
+
+    /// Number of peers still downloading (leechers)
+    pub incomplete: u64,
+}
+
+/// How long a tracker has to answer an announce before we move on to the next one
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The trackers of a torrent, in tiers.
+///
+/// Tiers are tried in order, and the trackers of a tier in a random order.
+/// A tracker which answers moves to the front of its tier, so that it is tried first next time.
+/// @link: https://www.bittorrent.org/beps/bep_0012.html
+#[derive(Debug, Default)]
+pub struct TrackerList {
+    tiers: Mutex<Vec<Vec<String>>>,
+
+    /// The last `tracker id` given by each tracker
+    tracker_ids: Mutex<HashMap<String, String>>,
+}
+
+impl TrackerList {
+    pub fn new(tiers: Vec<Vec<String>>) -> TrackerList {
+        let mut rng = rand::rng();
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        TrackerList {
+            tiers: Mutex::new(tiers),
+            tracker_ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The tiers of trackers, in the order they are tried.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.tiers.lock().expect("Locking tracker list").clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.lock().expect("Locking tracker list").is_empty()
+    }
+
+    /// Announce to the first tracker which answers.
+    ///
+    /// Each tracker gets `TRACKER_TIMEOUT` to answer, so that an unreachable tracker doesn't hold up the rest of its tier.
+    pub async fn announce(
+        &self,
+        query_params: &QueryParams,
+        info_hash: &[u8; 20],
+    ) -> Result<TrackerResponse, Error> {
+        let mut last_error = anyhow!("No tracker to announce to");
+        for (tier_index, tier) in self.tiers().iter().enumerate() {
+            for tracker in tier {
+                let mut query_params = query_params.clone();
+                query_params.trackerid = self.lock_tracker_ids().get(tracker).cloned();
+                let response = timeout(
+                    TRACKER_TIMEOUT,
+                    get_tracker_info(tracker, query_params, info_hash),
+                )
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Tracker didn't answer in {:?}", TRACKER_TIMEOUT)));
+                match response {
+                    Ok(response) => {
+                        self.promote(tier_index, tracker);
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.lock_tracker_ids()
+                                .insert(tracker.clone(), tracker_id.clone());
+                        }
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!("Tracker {} failed: {:?}", tracker, e);
+                        last_error = e.context(format!("Announcing to {}", tracker));
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn lock_tracker_ids(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.tracker_ids.lock().expect("Locking tracker ids")
+    }
+
+    /// Move a tracker which answered to the front of its tier.
+    fn promote(&self, tier_index: usize, tracker: &str) {
+        let mut tiers = self.tiers.lock().expect("Locking tracker list");
+        let Some(tier) = tiers.get_mut(tier_index) else {
+            return;
+        };
+        if let Some(position) = tier.iter().position(|url| url == tracker) {
+            let tracker = tier.remove(position);
+            tier.insert(0, tracker);
+        }
+    }
+}
+
+/// Response to an HTTP scrape request
+#[derive(Deserialize, Debug)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+
+    /// The statistics of each torrent, by info hash
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+/// Get the scrape URL of an HTTP tracker from its announce URL.
+///
+/// The last part of the path must start with `announce`, which is replaced with `scrape`:
+/// `http://example.com/x/announce.php?a=b` is scraped at `http://example.com/x/scrape.php?a=b`.
+/// Trackers whose announce URL doesn't follow this convention don't support scraping.
+/// @link: https://wiki.theory.org/BitTorrentSpecification#Tracker_.27scrape.27_Convention
+pub fn scrape_url(announce: &Url) -> Option<Url> {
+    let path = announce.path();
+    let (directory, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    let mut url = announce.clone();
+    url.set_path(&format!("{directory}/scrape{rest}"));
+    Some(url)
+}
+
+/// Get the number of seeders, leechers and completed downloads of torrents from a tracker,
+/// without announcing ourselves.
+///
+/// All the info hashes are scraped at once, the statistics are returned in the same order.
+/// Torrents the tracker doesn't know have all their numbers at 0.
+pub async fn scrape(endpoint: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeFile>, Error> {
+    let url = Url::parse(endpoint).with_context(|| format!("Parsing tracker URL {}", endpoint))?;
+    match url.scheme() {
+        "udp" => UdpTracker::new(&url).await?.scrape(info_hashes).await,
+        "http" | "https" => {
+            let url = scrape_url(&url).ok_or(anyhow!("Tracker {} can't be scraped", endpoint))?;
+            get_http_scrape(url, info_hashes).await
+        }
+        scheme => Err(anyhow!("Unsupported tracker protocol: {}", scheme)),
+    }
+}
+
+async fn get_http_scrape(mut url: Url, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeFile>, Error> {
+    // One info_hash parameter per torrent, added to the ones already in the URL
+    let mut query = url.query().map(str::to_string).unwrap_or_default();
+    for info_hash in info_hashes {
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str("info_hash=");
+        query.extend(info_hash.iter().map(|b| format!("%{:02x}", b)));
+    }
+    url.set_query(Some(&query));
+
+    let response = Client::new().get(url).send().await?.error_for_status()?;
+    let response = response.bytes().await?;
+    let mut response: ScrapeResponse =
+        serde_bencode::from_bytes(&response).context("Parsing scrape response")?;
+    if let Some(failure_reason) = response.failure_reason {
+        return Err(anyhow!("Tracker failure: {}", failure_reason));
+    }
+
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            response
+                .files
+                .remove(serde_bytes::Bytes::new(info_hash))
+                .unwrap_or_default()
+        })
+        .collect())
 }
 
-/// Get the tracker information
+/// Get the tracker information, over HTTP or UDP depending on the scheme of `endpoint`.
 pub async fn get_tracker_info(
     endpoint: &str,
     query_params: QueryParams,
-    info_hash: String,
+    info_hash: &[u8; 20],
+) -> Result<TrackerResponse, Error> {
+    let url = Url::parse(endpoint).with_context(|| format!("Parsing tracker URL {}", endpoint))?;
+    match url.scheme() {
+        "udp" => {
+            UdpTracker::new(&url)
+                .await?
+                .announce(info_hash, &query_params)
+                .await
+        }
+        "http" | "https" => get_http_tracker_info(url, query_params, info_hash).await,
+        scheme => Err(anyhow!("Unsupported tracker protocol: {}", scheme)),
+    }
+}
+
+async fn get_http_tracker_info(
+    mut url: Url,
+    query_params: QueryParams,
+    info_hash: &[u8; 20],
 ) -> Result<TrackerResponse, Error> {
     // Create a reqwest client
     let client = Client::new();
-    let mut url = Url::from_str(endpoint).expect("Parsing tracker URL");
 
-    let encoded_req = serde_urlencoded::to_string(query_params).unwrap();
+    // The info hash is raw bytes, each of them is URL encoded
+    let info_hash = info_hash
+        .iter()
+        .map(|b| format!("%{:02x}", b))
+        .collect::<String>();
+    let encoded_req = serde_urlencoded::to_string(query_params)?;
     url.set_query(Some(
         format!("{encoded_req}&info_hash={}", info_hash).as_str(),
     ));
     // println!("URL: {:?}", url);
-    let response = client.get(url).send().await?;
+    let response = client.get(url).send().await?.error_for_status()?;
     let response = response.bytes().await?;
-    let response: TrackerResponse =
-        serde_bencode::from_bytes::<TrackerResponse>(&response).expect("Parsing tracker response");
-
     // println!("Response: {:?}", response);
+    TrackerResponse::from_bytes(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+
+    fn parse(bytes: &[u8]) -> Result<TrackerResponse, Error> {
+        TrackerResponse::from_bytes(bytes)
+    }
+
+    #[test]
+    fn failure_reason_is_an_error() {
+        let error = parse(b"d14:failure reason20:torrent unregisterede").unwrap_err();
+        assert!(
+            error.to_string().contains("torrent unregistered"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn warning_message_keeps_the_response() {
+        let mut bytes =
+            b"d8:completei5e10:incompletei2e8:intervali1800e12:min intervali60e5:peers6:".to_vec();
+        bytes.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        bytes.extend(b"10:tracker id3:abc15:warning message10:be carefule");
+
+        let response = parse(&bytes).unwrap();
+        assert_eq!(response.warning_message.as_deref(), Some("be careful"));
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!((response.complete, response.incomplete), (Some(5), Some(2)));
+        assert_eq!(
+            response.addresses(),
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn dictionary_peer_lists() {
+        let bytes = b"d8:intervali900e5:peersl\
+            d2:ip8:10.0.0.27:peer id20:-XX0001-0123456789ab4:porti6881ee\
+            d2:ip11:example.com4:porti80ee\
+            d2:ip3:::14:porti51413ee\
+            ee";
+        let response = parse(bytes).unwrap();
+        // Domain names are skipped, IPv6 addresses are kept
+        assert_eq!(
+            response.addresses(),
+            vec![
+                "10.0.0.2:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:51413".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_ipv6_peers() {
+        let mut bytes = b"d8:intervali900e5:peers6:".to_vec();
+        bytes.extend([10, 0, 0, 3, 0x1a, 0xe1]);
+        bytes.extend(b"6:peers636:");
+        bytes.extend(Ipv6Addr::LOCALHOST.octets());
+        bytes.extend([0x1a, 0xe1]);
+        bytes.extend("2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend([0, 80]);
+        bytes.extend(b"e");
+
+        let addresses = parse(&bytes).unwrap().addresses();
+        assert_eq!(
+            addresses,
+            vec![
+                "10.0.0.3:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6881".parse().unwrap(),
+                "[2001:db8::7]:80".parse().unwrap(),
+            ]
+        );
+        assert!(addresses[1..]
+            .iter()
+            .all(|address| matches!(address.ip(), IpAddr::V6(_))));
+
+        // Only IPv6 peers, without the `peers` key
+        let mut bytes = b"d8:intervali900e6:peers618:".to_vec();
+        bytes.extend(Ipv6Addr::LOCALHOST.octets());
+        bytes.extend([0, 1]);
+        bytes.extend(b"e");
+        assert_eq!(
+            parse(&bytes).unwrap().addresses(),
+            vec!["[::1]:1".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn truncated_compact_peers_are_rejected() {
+        let mut bytes = b"d8:intervali900e5:peers5:".to_vec();
+        bytes.extend([10, 0, 0, 3, 0x1a]);
+        bytes.extend(b"e");
+        assert!(parse(&bytes).is_err());
 
-    Ok(response)
+        let mut bytes = b"d8:intervali900e6:peers617:".to_vec();
+        bytes.extend([0; 17]);
+        bytes.extend(b"e");
+        assert!(parse(&bytes).is_err());
+    }
 }
@@ -0,0 +1,426 @@
+use crate::structs::peers::{compact_addresses, PeerList, COMPACT_V4_LEN, COMPACT_V6_LEN};
+use crate::utils::trackers::{QueryParams, ScrapeFile, TrackerResponse};
+use anyhow::{anyhow, Context, Error};
+use rand::random;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
+
+/// Magic constant identifying the protocol in connect requests
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection ID may be used for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Requests are sent again after 15 * 2 ^ n seconds without a response
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// BEP 15 goes up to n = 8 (more than an hour). Announces wait for the answer,
+/// so a tracker gets one retransmission: an unreachable tracker fails after 45 seconds
+const MAX_RETRANSMISSIONS: u32 = 1;
+
+/// Most info hashes a single scrape request can hold
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Connection IDs received from each tracker, with the time they were received
+static CONNECTION_IDS: LazyLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A tracker speaking the UDP tracker protocol.
+///
+/// Every request starts with a connection ID obtained from a connect request,
+/// and is retransmitted with an exponential backoff until the tracker answers.
+/// @link: https://www.bittorrent.org/beps/bep_0015.html
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    address: SocketAddr,
+
+    /// How long the first attempt of a request waits for its response
+    retransmit_timeout: Duration,
+}
+
+impl UdpTracker {
+    /// Resolve the tracker of a `udp://host:port` URL.
+    pub async fn new(url: &Url) -> Result<UdpTracker, Error> {
+        let host = url
+            .host_str()
+            .ok_or(anyhow!("Missing tracker host in {}", url))?;
+        let port = url
+            .port()
+            .ok_or(anyhow!("Missing tracker port in {}", url))?;
+        let address = lookup_host((host, port))
+            .await
+            .with_context(|| format!("Resolving tracker {}", host))?
+            .next()
+            .ok_or(anyhow!("No address for tracker {}", host))?;
+
+        let local: SocketAddr = if address.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+        Ok(UdpTracker {
+            socket,
+            address,
+            retransmit_timeout: RETRANSMIT_TIMEOUT,
+        })
+    }
+
+    /// Announce ourselves to the tracker, and get peers for the torrent.
+    ///
+    /// Announce request:
+    /// connection_id (8 bytes), action (4 bytes), transaction_id (4 bytes), info_hash (20 bytes),
+    /// peer_id (20 bytes), downloaded (8 bytes), left (8 bytes), uploaded (8 bytes), event (4 bytes),
+    /// IP address (4 bytes), key (4 bytes), num_want (4 bytes), port (2 bytes)
+    ///
+    /// Announce response:
+    /// action (4 bytes), transaction_id (4 bytes), interval (4 bytes), leechers (4 bytes),
+    /// seeders (4 bytes), then 6 bytes per peer: IP address (4 bytes) and port (2 bytes).
+    /// Trackers reached over IPv6 answer with IPv6 peers instead: 18 bytes per peer, IP address (16 bytes) and port (2 bytes)
+    pub async fn announce(
+        &self,
+        info_hash: &[u8; 20],
+        query_params: &QueryParams,
+    ) -> Result<TrackerResponse, Error> {
+        let mut payload = Vec::with_capacity(82);
+        payload.extend_from_slice(info_hash);
+        payload.extend_from_slice(query_params.peer_id.as_bytes());
+        payload.extend_from_slice(&query_params.downloaded.to_be_bytes());
+        payload.extend_from_slice(&query_params.left.to_be_bytes());
+        payload.extend_from_slice(&query_params.uploaded.to_be_bytes());
+        let event = query_params.event.map_or(0, |event| event.udp_id());
+        payload.extend_from_slice(&event.to_be_bytes());
+        // IP address: the one the request comes from
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&random::<u32>().to_be_bytes());
+        // Number of peers wanted: the tracker's default
+        payload.extend_from_slice(&(-1i32).to_be_bytes());
+        payload.extend_from_slice(&query_params.port.to_be_bytes());
+        if payload.len() != 82 {
+            return Err(anyhow!("Peer ID must be 20 bytes long"));
+        }
+
+        let response = self.request(ACTION_ANNOUNCE, &payload).await?;
+        if response.len() < 12 {
+            return Err(anyhow!("Announce response is too short"));
+        }
+        let interval = read_u32(&response, 0);
+        let leechers = read_u32(&response, 4);
+        let seeders = read_u32(&response, 8);
+        let address_len = if self.address.is_ipv6() {
+            COMPACT_V6_LEN
+        } else {
+            COMPACT_V4_LEN
+        };
+        let peers = compact_addresses(&response[12..], address_len);
+
+        Ok(TrackerResponse {
+            interval: Some(interval as u64),
+            complete: Some(seeders as u64),
+            incomplete: Some(leechers as u64),
+            peers: Some(PeerList(peers)),
+            ..Default::default()
+        })
+    }
+
+    /// Get the number of seeders, completed downloads and leechers of torrents.
+    ///
+    /// Scrape request:
+    /// connection_id (8 bytes), action (4 bytes), transaction_id (4 bytes), then 20 bytes per info_hash
+    ///
+    /// Scrape response:
+    /// action (4 bytes), transaction_id (4 bytes), then for each info_hash, in the order of the request:
+    /// seeders (4 bytes), completed (4 bytes), leechers (4 bytes)
+    pub async fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeFile>, Error> {
+        let mut files = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let payload = chunk.concat();
+            let response = self.request(ACTION_SCRAPE, &payload).await?;
+            if response.len() < chunk.len() * 12 {
+                return Err(anyhow!("Scrape response is too short"));
+            }
+            files.extend(
+                response
+                    .chunks_exact(12)
+                    .take(chunk.len())
+                    .map(|stats| ScrapeFile {
+                        complete: read_u32(stats, 0) as u64,
+                        downloaded: read_u32(stats, 4) as u64,
+                        incomplete: read_u32(stats, 8) as u64,
+                    }),
+            );
+        }
+        Ok(files)
+    }
+
+    /// Send a request with a valid connection ID, retransmitting it until the tracker answers.
+    /// Returns the response, without its action and transaction ID.
+    async fn request(&self, action: u32, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        for attempt in 0..=MAX_RETRANSMISSIONS {
+            // The connection ID may expire between two retransmissions
+            let connection_id = match self.cached_connection_id() {
+                Some(connection_id) => connection_id,
+                None => self.connect().await?,
+            };
+            let response = self
+                .exchange(
+                    connection_id,
+                    action,
+                    payload,
+                    self.retransmit_timeout(attempt),
+                )
+                .await;
+            match response {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => continue,
+                Err(e) => {
+                    // The tracker may have rejected our connection ID: get a new one next time
+                    self.forget_connection_id();
+                    return Err(e);
+                }
+            }
+        }
+        Err(anyhow!("Tracker {} is not responding", self.address))
+    }
+
+    /// Get a new connection ID from the tracker.
+    ///
+    /// Connect request: protocol_id (8 bytes), action (4 bytes), transaction_id (4 bytes)
+    /// Connect response: action (4 bytes), transaction_id (4 bytes), connection_id (8 bytes)
+    async fn connect(&self) -> Result<u64, Error> {
+        for attempt in 0..=MAX_RETRANSMISSIONS {
+            let response = self
+                .exchange(
+                    PROTOCOL_ID,
+                    ACTION_CONNECT,
+                    &[],
+                    self.retransmit_timeout(attempt),
+                )
+                .await?;
+            let Some(response) = response else {
+                continue;
+            };
+            let connection_id: [u8; 8] = response
+                .get(..8)
+                .ok_or(anyhow!("Connect response is too short"))?
+                .try_into()?;
+            let connection_id = u64::from_be_bytes(connection_id);
+            CONNECTION_IDS
+                .lock()
+                .expect("Locking connection IDs")
+                .insert(self.address, (connection_id, Instant::now()));
+            return Ok(connection_id);
+        }
+        Err(anyhow!("Tracker {} is not responding", self.address))
+    }
+
+    fn retransmit_timeout(&self, attempt: u32) -> Duration {
+        self.retransmit_timeout * 2u32.pow(attempt)
+    }
+
+    fn cached_connection_id(&self) -> Option<u64> {
+        let connection_ids = CONNECTION_IDS.lock().expect("Locking connection IDs");
+        let (connection_id, received) = connection_ids.get(&self.address)?;
+        (received.elapsed() < CONNECTION_ID_LIFETIME).then_some(*connection_id)
+    }
+
+    fn forget_connection_id(&self) {
+        CONNECTION_IDS
+            .lock()
+            .expect("Locking connection IDs")
+            .remove(&self.address);
+    }
+
+    /// Send a request once, and wait up to `wait` for its response.
+    /// Returns `None` when the tracker didn't answer in time.
+    async fn exchange(
+        &self,
+        connection_id: u64,
+        action: u32,
+        payload: &[u8],
+        wait: Duration,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let transaction_id: u32 = random();
+        let mut request = Vec::with_capacity(16 + payload.len());
+        request.extend_from_slice(&connection_id.to_be_bytes());
+        request.extend_from_slice(&action.to_be_bytes());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+        request.extend_from_slice(payload);
+        self.socket.send(&request).await?;
+
+        let receive = async {
+            let mut buffer = vec![0u8; 65536];
+            loop {
+                let length = self.socket.recv(&mut buffer).await?;
+                let response = &buffer[..length];
+                // Drop anything which doesn't answer this request (a late response to an earlier one)
+                if length < 8 || read_u32(response, 4) != transaction_id {
+                    continue;
+                }
+                return match read_u32(response, 0) {
+                    ACTION_ERROR => Err(anyhow!(
+                        "Tracker error: {}",
+                        String::from_utf8_lossy(&response[8..])
+                    )),
+                    response_action if response_action == action => Ok(response[8..].to_vec()),
+                    response_action => Err(anyhow!(
+                        "Unexpected action {} in tracker response",
+                        response_action
+                    )),
+                };
+            }
+        };
+
+        match timeout(wait, receive).await {
+            Ok(response) => response.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    const CONNECTION_ID: u64 = 0x1122_3344_5566_7788;
+
+    fn query_params() -> QueryParams {
+        QueryParams {
+            peer_id: "-RS0001-000000000000".to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            compact: 1,
+            trackerid: None,
+            event: None,
+        }
+    }
+
+    /// A tracker answering connect and announce requests on the loopback, which ignores the first `dropped` requests.
+    /// Returns its URL, and the action of every request it received.
+    async fn stub_tracker(dropped: usize) -> (Url, Arc<Mutex<Vec<u32>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        let actions = Arc::new(Mutex::new(vec![]));
+        let received = actions.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = &buffer[..length];
+                let connection_id = u64::from_be_bytes(request[..8].try_into().unwrap());
+                let action = read_u32(request, 8);
+                let count = {
+                    let mut received = received.lock().unwrap();
+                    received.push(action);
+                    received.len()
+                };
+                if count <= dropped {
+                    continue;
+                }
+
+                let mut response = vec![];
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        response.extend_from_slice(&request[12..16]);
+                        response.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE if connection_id == CONNECTION_ID => {
+                        assert_eq!(length, 98);
+                        response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        response.extend_from_slice(&request[12..16]);
+                        // Interval, leechers, seeders, then a single peer
+                        for value in [1800u32, 2, 3] {
+                            response.extend_from_slice(&value.to_be_bytes());
+                        }
+                        response.extend_from_slice(&[10, 0, 0, 1]);
+                        response.extend_from_slice(&6881u16.to_be_bytes());
+                    }
+                    _ => {
+                        response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                        response.extend_from_slice(&request[12..16]);
+                        response.extend_from_slice(b"Invalid connection ID");
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (url, actions)
+    }
+
+    #[tokio::test]
+    async fn announce_connects_then_reuses_the_connection_id() {
+        let (url, actions) = stub_tracker(0).await;
+        let tracker = UdpTracker::new(&url).await.unwrap();
+
+        let response = tracker.announce(&[1; 20], &query_params()).await.unwrap();
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.incomplete, Some(2));
+        assert_eq!(response.complete, Some(3));
+        let peers = response.peers.unwrap().0;
+        assert_eq!(
+            peers,
+            vec![SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881))]
+        );
+
+        // The connection ID is still valid: the second announce goes out right away
+        tracker.announce(&[1; 20], &query_params()).await.unwrap();
+        assert_eq!(
+            *actions.lock().unwrap(),
+            vec![ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_ANNOUNCE]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_retransmitted_after_a_dropped_packet() {
+        let (url, actions) = stub_tracker(1).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        tracker.retransmit_timeout = Duration::from_millis(100);
+
+        let response = tracker.announce(&[2; 20], &query_params()).await.unwrap();
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(
+            *actions.lock().unwrap(),
+            vec![ACTION_CONNECT, ACTION_CONNECT, ACTION_ANNOUNCE]
+        );
+    }
+
+    #[tokio::test]
+    async fn unresponsive_tracker_fails() {
+        let (url, actions) = stub_tracker(usize::MAX).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        tracker.retransmit_timeout = Duration::from_millis(50);
+
+        assert!(tracker.announce(&[3; 20], &query_params()).await.is_err());
+        assert_eq!(
+            actions.lock().unwrap().len(),
+            MAX_RETRANSMISSIONS as usize + 1
+        );
+    }
+}